@@ -0,0 +1,682 @@
+//! Assembler for Chip8 programs using the mnemonics of [Instruction](crate::chip8::Instruction)
+//!
+//! A line holds an optional label, an instruction or directive and an optional comment, e.g.
+//!
+//! ```text
+//! SPEED   EQU 2          ; constant
+//! start:  LDI sprite     ; label and instruction
+//!         DRAW V0, V1, 5
+//!         JMP start
+//! sprite: DB 0xF0, 0x90, 0xF0, 0x90, 0xF0
+//!         INCLUDE "more.asm"
+//! ```
+//!
+//! Operands are registers (V0-VF) or expressions of numbers (decimal, 0x hex or 0b binary), labels and constants joined by + and -.
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// Maximum depth of nested includes
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// An error in the source with its location
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// Name of the source file
+    pub file: String,
+    /// Line number starting at 1
+    pub line: usize,
+    /// Column number starting at 1
+    pub column: usize,
+    /// What is wrong
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+impl Error for AsmError {}
+
+/// Assemble source into a binary to be loaded at load_address. Includes are relative to the current directory
+pub fn assemble(source: &str, load_address: usize) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::new(load_address);
+    assembler.read("<source>", source, Path::new("."), 0)?;
+    assembler.encode()
+}
+
+/// Assemble a source file into a binary to be loaded at load_address. Includes are relative to the including file
+pub fn assemble_file(path: &Path, load_address: usize) -> Result<Vec<u8>, AsmError> {
+    let source = fs::read_to_string(path).map_err(|error| AsmError {
+        file: path.display().to_string(),
+        line: 0,
+        column: 0,
+        message: error.to_string(),
+    })?;
+    let mut assembler = Assembler::new(load_address);
+    assembler.read(
+        &path.display().to_string(),
+        &source,
+        path.parent().unwrap_or(Path::new(".")),
+        0,
+    )?;
+    assembler.encode()
+}
+
+/// Location of a token in the source
+#[derive(Debug, Clone)]
+struct Location {
+    file: String,
+    line: usize,
+    column: usize,
+}
+
+impl Location {
+    /// An error at this location
+    fn error(&self, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+
+    /// The location offset columns to the right
+    fn offset(&self, columns: usize) -> Location {
+        Location {
+            column: self.column + columns,
+            ..self.clone()
+        }
+    }
+}
+
+/// A piece of text in the source
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    location: Location,
+}
+
+/// An instruction or data directive at an address
+#[derive(Debug)]
+struct Statement {
+    mnemonic: Token,
+    operands: Vec<Token>,
+}
+
+/// Value of a symbol
+#[derive(Debug)]
+enum Symbol {
+    /// A label at an address
+    Label(usize),
+    /// A constant given by an expression
+    Constant(Token),
+}
+
+/// Two pass assembler. The first pass reads the source, lays out the statements and collects the symbols. The second pass encodes the statements
+struct Assembler {
+    load_address: usize,
+    address: usize,
+    statements: Vec<Statement>,
+    symbols: HashMap<String, Symbol>,
+}
+
+impl Assembler {
+    fn new(load_address: usize) -> Self {
+        Assembler {
+            load_address,
+            address: load_address,
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+        }
+    }
+
+    /// First pass over the source in a file. Includes are read recursively relative to dir
+    fn read(&mut self, file: &str, source: &str, dir: &Path, depth: usize) -> Result<(), AsmError> {
+        for (index, text) in source.lines().enumerate() {
+            let line = Location {
+                file: file.to_string(),
+                line: index + 1,
+                column: 1,
+            };
+            let mut words = split_words(text, &line);
+
+            // Label
+            if let Some(word) = words.first() {
+                if let Some(name) = word.text.strip_suffix(':') {
+                    self.define(name, Symbol::Label(self.address), &word.location)?;
+                    words.remove(0);
+                }
+            }
+
+            let Some(mnemonic) = words.first().cloned() else {
+                continue;
+            };
+
+            // Constant
+            if words.len() > 1 && words[1].text.eq_ignore_ascii_case("EQU") {
+                let value = rest_of_line(text, &words[1])
+                    .ok_or_else(|| words[1].location.error("missing value of constant"))?;
+                self.define(&mnemonic.text, Symbol::Constant(value), &mnemonic.location)?;
+                continue;
+            }
+
+            let operands = match words.get(1) {
+                Some(first) => split_operands(text, first),
+                None => Vec::new(),
+            };
+
+            if mnemonic.text.eq_ignore_ascii_case("INCLUDE") {
+                self.include(&mnemonic, &operands, dir, depth)?;
+                continue;
+            }
+
+            self.address += size(&mnemonic, &operands)?;
+            self.statements.push(Statement { mnemonic, operands });
+        }
+
+        Ok(())
+    }
+
+    /// Read an included file
+    fn include(
+        &mut self,
+        mnemonic: &Token,
+        operands: &[Token],
+        dir: &Path,
+        depth: usize,
+    ) -> Result<(), AsmError> {
+        let [operand] = operands else {
+            return Err(mnemonic.location.error("expected one file name"));
+        };
+        let name = operand
+            .text
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+            .ok_or_else(|| operand.location.error("expected a quoted file name"))?;
+
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(operand.location.error("includes nested too deep"));
+        }
+
+        let path: PathBuf = dir.join(name);
+        let source = fs::read_to_string(&path).map_err(|error| {
+            operand
+                .location
+                .error(format!("could not read {}: {}", path.display(), error))
+        })?;
+
+        self.read(
+            &path.display().to_string(),
+            &source,
+            path.parent().unwrap_or(dir),
+            depth + 1,
+        )
+    }
+
+    /// Define a symbol
+    fn define(&mut self, name: &str, symbol: Symbol, location: &Location) -> Result<(), AsmError> {
+        if !is_identifier(name) {
+            return Err(location.error(format!("invalid name {}", name)));
+        }
+
+        if self.symbols.insert(name.to_string(), symbol).is_some() {
+            return Err(location.error(format!("{} is already defined", name)));
+        }
+
+        Ok(())
+    }
+
+    /// Second pass. Encode all statements
+    fn encode(&self) -> Result<Vec<u8>, AsmError> {
+        let mut binary = Vec::with_capacity(self.address - self.load_address);
+
+        for statement in &self.statements {
+            self.encode_statement(statement, &mut binary)?;
+        }
+
+        Ok(binary)
+    }
+
+    /// Encode one statement
+    fn encode_statement(
+        &self,
+        statement: &Statement,
+        binary: &mut Vec<u8>,
+    ) -> Result<(), AsmError> {
+        let mnemonic = statement.mnemonic.text.to_ascii_uppercase();
+        let ops = &statement.operands;
+        let location = &statement.mnemonic.location;
+        let count = |n: usize| -> Result<(), AsmError> {
+            if ops.len() == n {
+                Ok(())
+            } else {
+                Err(location.error(format!("{} expects {} operand(s)", mnemonic, n)))
+            }
+        };
+
+        let opcode: u16 = match mnemonic.as_str() {
+            "DB" => {
+                for op in ops {
+                    binary.push(self.byte(op)?);
+                }
+                return Ok(());
+            }
+            "DW" => {
+                for op in ops {
+                    binary.extend(self.value(op, 0, 0xFFFF)?.to_be_bytes());
+                }
+                return Ok(());
+            }
+            "CLS" | "RET" | "SCR" | "SCL" | "EXIT" | "LOW" | "HIGH" | "AUDIO" => {
+                count(0)?;
+                match mnemonic.as_str() {
+                    "CLS" => 0x00E0,
+                    "RET" => 0x00EE,
+                    "SCR" => 0x00FB,
+                    "SCL" => 0x00FC,
+                    "EXIT" => 0x00FD,
+                    "LOW" => 0x00FE,
+                    "HIGH" => 0x00FF,
+                    _ => 0xF002,
+                }
+            }
+            "SCD" | "SCU" => {
+                count(1)?;
+                let base = if mnemonic == "SCD" { 0x00C0 } else { 0x00D0 };
+                base | self.value(&ops[0], 0, 0xF)?
+            }
+            "PLANE" => {
+                count(1)?;
+                0xF001 | self.value(&ops[0], 0, 0x3)? << 8
+            }
+            "SYS" | "JMP" | "CALL" | "LDI" | "JMPZ" => {
+                count(1)?;
+                let base = match mnemonic.as_str() {
+                    "SYS" => 0x0000,
+                    "JMP" => 0x1000,
+                    "CALL" => 0x2000,
+                    "LDI" => 0xA000,
+                    _ => 0xB000,
+                };
+                base | self.value(&ops[0], 0, 0xFFF)?
+            }
+            "LDIL" => {
+                count(1)?;
+                binary.extend(0xF000u16.to_be_bytes());
+                self.value(&ops[0], 0, 0xFFFF)?
+            }
+            "SKEB" | "SKNEB" | "LDB" | "ADDB" | "RND" => {
+                count(2)?;
+                let base = match mnemonic.as_str() {
+                    "SKEB" => 0x3000,
+                    "SKNEB" => 0x4000,
+                    "LDB" => 0x6000,
+                    "ADDB" => 0x7000,
+                    _ => 0xC000,
+                };
+                base | register(&ops[0])? << 8 | self.byte(&ops[1])? as u16
+            }
+            "SKE" | "SKNE" | "LD" | "OR" | "AND" | "XOR" | "ADD" | "SUB" | "SUBR" | "SREGR"
+            | "LREGR" => {
+                count(2)?;
+                let base = match mnemonic.as_str() {
+                    "SKE" => 0x5000,
+                    "SKNE" => 0x9000,
+                    "LD" => 0x8000,
+                    "OR" => 0x8001,
+                    "AND" => 0x8002,
+                    "XOR" => 0x8003,
+                    "ADD" => 0x8004,
+                    "SUB" => 0x8005,
+                    "SUBR" => 0x8007,
+                    "SREGR" => 0x5002,
+                    _ => 0x5003,
+                };
+                base | register(&ops[0])? << 8 | register(&ops[1])? << 4
+            }
+            "SHR" | "SHL" => {
+                // VY is optional and defaults to VX
+                if ops.is_empty() || ops.len() > 2 {
+                    count(2)?;
+                }
+                let base = if mnemonic == "SHR" { 0x8006 } else { 0x800E };
+                let x = register(&ops[0])?;
+                let y = match ops.get(1) {
+                    Some(op) => register(op)?,
+                    None => x,
+                };
+                base | x << 8 | y << 4
+            }
+            "DRAW" => {
+                count(3)?;
+                0xD000
+                    | register(&ops[0])? << 8
+                    | register(&ops[1])? << 4
+                    | self.value(&ops[2], 0, 0xF)?
+            }
+            "SKP" | "SKNP" | "LDFT" | "LDKP" | "LDTT" | "LDST" | "ADDI" | "FONT" | "HFONT"
+            | "BCD" | "SREG" | "LREG" | "SRPL" | "LRPL" | "PITCH" => {
+                count(1)?;
+                let base = match mnemonic.as_str() {
+                    "SKP" => 0xE09E,
+                    "SKNP" => 0xE0A1,
+                    "LDFT" => 0xF007,
+                    "LDKP" => 0xF00A,
+                    "LDTT" => 0xF015,
+                    "LDST" => 0xF018,
+                    "ADDI" => 0xF01E,
+                    "FONT" => 0xF029,
+                    "HFONT" => 0xF030,
+                    "BCD" => 0xF033,
+                    "SREG" => 0xF055,
+                    "LREG" => 0xF065,
+                    "SRPL" => 0xF075,
+                    "LRPL" => 0xF085,
+                    _ => 0xF03A,
+                };
+                base | register(&ops[0])? << 8
+            }
+            _ => return Err(location.error(format!("unknown instruction {}", mnemonic))),
+        };
+
+        binary.extend(opcode.to_be_bytes());
+
+        Ok(())
+    }
+
+    /// Evaluate an operand as a byte. Negative values are stored as two's complement
+    fn byte(&self, token: &Token) -> Result<u8, AsmError> {
+        let value = self.evaluate(token, 0)?;
+        if (-128..=255).contains(&value) {
+            Ok(value as u8)
+        } else {
+            Err(token
+                .location
+                .error(format!("value {} out of range for a byte", value)))
+        }
+    }
+
+    /// Evaluate an operand and check that it is within min..=max
+    fn value(&self, token: &Token, min: i64, max: i64) -> Result<u16, AsmError> {
+        let value = self.evaluate(token, 0)?;
+        if (min..=max).contains(&value) {
+            Ok(value as u16)
+        } else {
+            Err(token
+                .location
+                .error(format!("value {} out of range {}..={}", value, min, max)))
+        }
+    }
+
+    /// Evaluate an expression of terms joined by + and -. Depth guards against constants defined in terms of themselves
+    fn evaluate(&self, token: &Token, depth: usize) -> Result<i64, AsmError> {
+        if depth > self.symbols.len() {
+            return Err(token.location.error("constant defined in terms of itself"));
+        }
+
+        let mut result = 0i64;
+        let mut sign = 1;
+        let mut start = 0;
+        let text = &token.text;
+
+        for (i, c) in text.char_indices().chain([(text.len(), '+')]) {
+            if c != '+' && c != '-' {
+                continue;
+            }
+
+            let term = text[start..i].trim();
+            let location = token
+                .location
+                .offset(start + (text[start..i].len() - text[start..i].trim_start().len()));
+
+            if term.is_empty() {
+                // A leading sign
+                if i == text.len() || result != 0 || start != 0 {
+                    return Err(location.error("missing term in expression"));
+                }
+            } else {
+                result = self
+                    .term(term, &location, depth)?
+                    .checked_mul(sign)
+                    .and_then(|value| result.checked_add(value))
+                    .ok_or_else(|| location.error("overflow in expression"))?;
+            }
+
+            sign = if c == '-' { -1 } else { 1 };
+            start = i + 1;
+        }
+
+        Ok(result)
+    }
+
+    /// Evaluate a number or a symbol
+    fn term(&self, term: &str, location: &Location, depth: usize) -> Result<i64, AsmError> {
+        if let Some(number) = number(term) {
+            return Ok(number);
+        }
+
+        match self.symbols.get(term) {
+            Some(Symbol::Label(address)) => Ok(*address as i64),
+            Some(Symbol::Constant(value)) => self.evaluate(value, depth + 1),
+            None if is_identifier(term) => {
+                Err(location.error(format!("undefined symbol {}", term)))
+            }
+            None => Err(location.error(format!("invalid number or name {}", term))),
+        }
+    }
+}
+
+/// Size in bytes of a statement
+fn size(mnemonic: &Token, operands: &[Token]) -> Result<usize, AsmError> {
+    match mnemonic.text.to_ascii_uppercase().as_str() {
+        "DB" => Ok(operands.len()),
+        "DW" => Ok(2 * operands.len()),
+        "LDIL" => Ok(4),
+        _ if is_identifier(&mnemonic.text) => Ok(2),
+        _ => Err(mnemonic
+            .location
+            .error(format!("unexpected {}", mnemonic.text))),
+    }
+}
+
+/// Parse a register V0-VF
+fn register(token: &Token) -> Result<u16, AsmError> {
+    let text = &token.text;
+    match (text.get(..1), text.get(1..)) {
+        (Some("V" | "v"), Some(digit)) if digit.len() == 1 => u16::from_str_radix(digit, 16)
+            .map_err(|_| token.location.error(format!("invalid register {}", text))),
+        _ => Err(token
+            .location
+            .error(format!("expected a register V0-VF, found {}", text))),
+    }
+}
+
+/// Parse a decimal, 0x hex or 0b binary number
+fn number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// A name of a label or constant
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Text of a line without the comment
+fn strip_comment(text: &str) -> &str {
+    // A ; inside a quoted file name is not a comment
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &text[..i],
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Split a line into whitespace separated words (ignoring the comment)
+fn split_words(text: &str, line: &Location) -> Vec<Token> {
+    let text = strip_comment(text);
+    let mut words = Vec::new();
+    let mut start = None;
+
+    for (i, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                words.push(Token {
+                    text: text[s..i].to_string(),
+                    location: line.offset(s),
+                });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    words
+}
+
+/// The rest of the line after a word (ignoring the comment), if any
+fn rest_of_line(text: &str, word: &Token) -> Option<Token> {
+    let start = word.location.column - 1 + word.text.len();
+    let rest = strip_comment(text).get(start..)?;
+    let trimmed = rest.trim();
+
+    (!trimmed.is_empty()).then(|| Token {
+        text: trimmed.to_string(),
+        location: word
+            .location
+            .offset(word.text.len() + rest.len() - rest.trim_start().len()),
+    })
+}
+
+/// Split the operands starting at the first operand word into comma separated tokens
+fn split_operands(text: &str, first: &Token) -> Vec<Token> {
+    let offset = first.location.column - 1;
+    let rest = &strip_comment(text)[offset..];
+    let mut operands = Vec::new();
+    let mut start = 0;
+
+    for raw in rest.split(',') {
+        operands.push(Token {
+            text: raw.trim().to_string(),
+            location: first
+                .location
+                .offset(start + raw.len() - raw.trim_start().len()),
+        });
+        start += raw.len() + 1;
+    }
+
+    operands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chip8::Platform,
+        disasm::{disassemble, Syntax},
+    };
+
+    /// Location and message of the error assembling source
+    fn error(source: &str) -> (usize, usize, String) {
+        let error = assemble(source, 0x200).unwrap_err();
+        assert_eq!(error.file, "<source>");
+        (error.line, error.column, error.message)
+    }
+
+    #[test]
+    fn errors_have_line_and_column() {
+        assert_eq!(
+            error("CLS\n  LDB V0, 1 + missing"),
+            (2, 15, "undefined symbol missing".to_string())
+        );
+        assert_eq!(
+            error("  LDB VG, 1"),
+            (1, 7, "invalid register VG".to_string())
+        );
+        assert_eq!(
+            error("LDB V0, 256"),
+            (1, 9, "value 256 out of range for a byte".to_string())
+        );
+        assert_eq!(
+            error("A EQU B\nB EQU A\nLDB V0, A"),
+            (1, 7, "constant defined in terms of itself".to_string())
+        );
+        assert_eq!(
+            error("LDI 0x7FFFFFFFFFFFFFFF + 1"),
+            (1, 26, "overflow in expression".to_string())
+        );
+        assert_eq!(error("FOO V0").2, "unknown instruction FOO");
+    }
+
+    #[test]
+    fn symbols_and_expressions() {
+        let source = "\
+SPEED   EQU 2
+STEP    EQU SPEED + 0x10 - 0b11
+start:  LDB V0, SPEED
+        ADDB V1, STEP
+        LDI sprite + 1
+        JMP start
+sprite: DB 0xF0, -1, end - start
+end:";
+        assert_eq!(
+            assemble(source, 0x200).unwrap(),
+            [0x60, 0x02, 0x71, 0x0F, 0xA2, 0x09, 0x12, 0x00, 0xF0, 0xFF, 0x0B]
+        );
+    }
+
+    #[test]
+    fn disassembly_assembles_to_the_same_program() {
+        let source = "\
+start:  CLS
+        LDB V0, 0x12
+        CALL draw
+        SKEB V0, 3
+        JMP start
+        EXIT
+draw:   LDI sprite
+        DRAW V0, V1, 2
+        ADD V2, V3
+        SHR V4, V5
+        LDKP V6
+        RET
+sprite: DB 0xFF, 0x81, 0x7E";
+        let program = assemble(source, 0x200).unwrap();
+        let listing: Vec<String> = disassemble(
+            &program,
+            0x200,
+            Platform::SchipModern,
+            Syntax::Chip8rs,
+            false,
+        )
+        .iter()
+        .map(|line| line.text.clone())
+        .collect();
+
+        assert!(listing.contains(&"DB 0xFF, 0x81, 0x7E".to_string()));
+        assert_eq!(assemble(&listing.join("\n"), 0x200).unwrap(), program);
+    }
+}
@@ -1,18 +1,25 @@
 //! A Chip8 model
+use std::{error::Error, fmt, ops::Range, str::FromStr};
+
 use crate::chip8::Instruction::*;
+use crate::random::{RandomSource, SplitMix64};
 
-/// Memory size in bytes
-const MEMORY_SIZE: usize = 4096;
+/// Memory size in bytes (XO-CHIP)
+const MEMORY_SIZE: usize = 0x10000;
 /// Program start
-const PROGRAM_START: usize = 0x200;
+pub const PROGRAM_START: usize = 0x200;
 /// Number of general purpose registers
-const NUMBER_OF_REGISTERS: usize = 16;
+pub const NUMBER_OF_REGISTERS: usize = 16;
 /// Size of stack
-const STACK_SIZE: usize = 16;
+pub const STACK_SIZE: usize = 16;
 /// Width of display in pixels
 pub const DISPLAY_WIDTH: usize = 64;
 /// Height of display in pixels
 pub const DISPLAY_HEIGHT: usize = 32;
+/// Width of display in pixels in high resolution mode (SUPER-CHIP)
+pub const HIRES_DISPLAY_WIDTH: usize = 128;
+/// Height of display in pixels in high resolution mode (SUPER-CHIP)
+pub const HIRES_DISPLAY_HEIGHT: usize = 64;
 /// Size of fonts in bytes
 const FONTS_SIZE: usize = 16 * 5;
 /// Default fonts
@@ -34,6 +41,37 @@ const FONTS: [u8; FONTS_SIZE] = [
     0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
+/// Start of big fonts in memory (right after the default fonts)
+const BIG_FONTS_START: usize = FONTS_SIZE;
+/// Size of big fonts in bytes
+const BIG_FONTS_SIZE: usize = 16 * 10;
+/// Big fonts (SUPER-CHIP)
+const BIG_FONTS: [u8; BIG_FONTS_SIZE] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+/// Size of the memory reserved for fonts at the start of memory
+const RESERVED_SIZE: usize = BIG_FONTS_START + BIG_FONTS_SIZE;
+/// Number of RPL user flags (SUPER-CHIP has 8, XO-CHIP has 16)
+const NUMBER_OF_FLAGS: usize = 16;
+/// Number of display bitplanes (XO-CHIP)
+pub const NUMBER_OF_PLANES: usize = 2;
+/// Size of the audio pattern buffer in bytes (XO-CHIP)
+pub const AUDIO_PATTERN_SIZE: usize = 16;
 /// Size of the keyboard
 pub const KEYBOARD_SIZE: usize = 16;
 
@@ -56,17 +94,34 @@ pub struct Chip8 {
     /// Stack
     stack: [usize; STACK_SIZE],
 
-    /// Display "buffer" output as 2-d array of bool
-    pub display: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    /// RPL user flags (SUPER-CHIP)
+    flags: [u8; NUMBER_OF_FLAGS],
+
+    /// Display "buffer" output as 2-d array of bitplanes, i.e. bit 0 is plane 1 and bit 1 is plane 2 giving four colors. Only the top left DISPLAY_WIDTH x DISPLAY_HEIGHT pixels are used in low resolution mode
+    pub display: [[u8; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT],
+    /// Bitplanes selected for drawing, clearing and scrolling (XO-CHIP)
+    plane: u8,
+    /// High resolution mode (SUPER-CHIP) is enabled
+    pub hires: bool,
     /// Display has been updated. Redraw the display on target and set to false
     pub display_update: bool,
     /// Keyboard input as array of bool
     pub keyboard: [bool; KEYBOARD_SIZE],
+    /// Platform being emulated
+    pub platform: Platform,
     /// Options/quirks
     pub quirks: Quirks,
+    /// Audio pattern buffer (XO-CHIP). None until a pattern is loaded, meaning a plain buzzer should be used
+    pub pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
+    /// Pitch register controlling the playback rate of the audio pattern (XO-CHIP)
+    pub pitch: u8,
+    /// Source of random numbers for RND
+    rng: Box<dyn RandomSource>,
+    /// Key pressed while waiting for a key with FX0A. The wait ends when it is released
+    key_wait: Option<u8>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Quirks {
     /// Quirk: AND, OR, XOR reset VF to zero
     pub vf_reset: bool,
@@ -80,45 +135,649 @@ pub struct Quirks {
     pub shifting: bool,
     /// Quirk: Jump with offset operation BNNN will work as BXNN
     pub jumping: bool,
+    /// Quirk: Wait for key operation FX0A takes a key as soon as it is held instead of when it is released
+    pub key_press: bool,
+}
+
+/// The outcome of a successful step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// An instruction was executed
+    Executed,
+    /// The program is waiting for a key press. The instruction will be executed again on the next step
+    WaitingForKey,
+    /// The program has executed the exit instruction (SUPER-CHIP). The instruction will be executed again on the next step
+    Exit,
+}
+
+/// An error when executing a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chip8Error {
+    /// Program counter of the failing instruction
+    pub pc: usize,
+    /// The failing instruction (0 if it couldn't be fetched)
+    pub opcode: u16,
+    /// What went wrong
+    pub cause: ErrorCause,
+}
+
+/// Causes of errors when executing a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCause {
+    /// The instruction is unknown or not available on the platform
+    UnknownInstruction,
+    /// A subroutine call with a full stack
+    StackOverflow,
+    /// A return with an empty stack
+    StackUnderflow,
+    /// An access to memory outside of the platform memory size at the address
+    MemoryOutOfBounds(usize),
+    /// A key number in a register is not on the keyboard
+    InvalidKey(u8),
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at pc {:04X} (instruction {:04X})",
+            self.cause, self.pc, self.opcode
+        )
+    }
+}
+
+impl Error for Chip8Error {}
+
+impl fmt::Display for ErrorCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCause::UnknownInstruction => write!(f, "unknown instruction"),
+            ErrorCause::StackOverflow => write!(f, "stack overflow"),
+            ErrorCause::StackUnderflow => write!(f, "stack underflow"),
+            ErrorCause::MemoryOutOfBounds(address) => {
+                write!(f, "memory access out of bounds at {:04X}", address)
+            }
+            ErrorCause::InvalidKey(key) => write!(f, "invalid key {:02X}", key),
+        }
+    }
+}
+
+/// An error when loading a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The program doesn't fit in the memory of the platform at the load address
+    ProgramTooLarge {
+        size: usize,
+        available: usize,
+        platform: Platform,
+    },
+    /// The load address overlaps the fonts or is outside of the memory of the platform
+    InvalidLoadAddress { address: usize, platform: Platform },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::ProgramTooLarge {
+                size,
+                available,
+                platform,
+            } => write!(
+                f,
+                "ROM too large for platform {}: {} bytes, {} bytes available",
+                platform, size, available
+            ),
+            LoadError::InvalidLoadAddress { address, platform } => write!(
+                f,
+                "invalid load address {:04X} for platform {}",
+                address, platform
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+/// Identifies a save state
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+/// Version of the save state format. Increment when the format changes. Version 2 added the random number generator state, version 3 the key_press quirk and the key waited for
+const STATE_VERSION: u16 = 3;
+/// Oldest version of the save state format that can be loaded
+const STATE_MIN_VERSION: u16 = 1;
+/// Save state value of no key
+const NO_KEY: u8 = 0xFF;
+
+/// An error when loading a save state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// The data is not a save state
+    InvalidFormat,
+    /// The save state has a version this build can't load
+    UnsupportedVersion(u16),
+    /// The save state ends too early
+    Truncated,
+    /// The save state has an unknown platform
+    InvalidPlatform(u8),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::InvalidFormat => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported save state version {} (expected {} to {})",
+                version, STATE_MIN_VERSION, STATE_VERSION
+            ),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::InvalidPlatform(platform) => {
+                write!(f, "invalid platform {} in save state", platform)
+            }
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// Reads the fields of a save state in order
+struct StateReader<'a> {
+    state: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.state.len() < len {
+            return Result::Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.state.split_at(len);
+        self.state = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+}
+
+/// Platforms (Chip8 variants) with their own instruction set, quirks, memory size, display size and speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Platform {
+    /// The original Chip8 on the COSMAC VIP
+    #[default]
+    CosmacVip,
+    /// CHIP-48 on the HP48 calculators
+    Chip48,
+    /// SUPER-CHIP 1.1 on the HP48 calculators
+    SchipLegacy,
+    /// SUPER-CHIP as implemented by modern interpreters like Octo
+    SchipModern,
+    /// XO-CHIP as defined by Octo
+    XoChip,
+}
+
+impl Platform {
+    /// Names of all platforms as used on the command line
+    pub const NAMES: [&'static str; 5] =
+        ["vip", "chip48", "schip-legacy", "schip-modern", "xo-chip"];
+
+    /// Quirks of the platform
+    pub fn quirks(&self) -> Quirks {
+        match self {
+            Platform::CosmacVip => Quirks {
+                vf_reset: true,
+                memory: true,
+                display_wait: true,
+                clipping: true,
+                shifting: false,
+                jumping: false,
+                key_press: false,
+            },
+            Platform::Chip48 | Platform::SchipModern => Quirks {
+                vf_reset: false,
+                memory: false,
+                display_wait: false,
+                clipping: true,
+                shifting: true,
+                jumping: true,
+                key_press: false,
+            },
+            Platform::SchipLegacy => Quirks {
+                vf_reset: false,
+                memory: false,
+                display_wait: true,
+                clipping: true,
+                shifting: true,
+                jumping: true,
+                key_press: false,
+            },
+            Platform::XoChip => Quirks {
+                vf_reset: false,
+                memory: true,
+                display_wait: false,
+                clipping: false,
+                shifting: false,
+                jumping: false,
+                key_press: false,
+            },
+        }
+    }
+
+    /// Default number of instructions per frame (at 60 frames per second)
+    pub fn instructions_per_frame(&self) -> u16 {
+        match self {
+            Platform::CosmacVip => 15,
+            Platform::Chip48 | Platform::SchipLegacy | Platform::SchipModern => 30,
+            Platform::XoChip => 1000,
+        }
+    }
+
+    /// Size of memory available to programs in bytes
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Platform::XoChip => MEMORY_SIZE,
+            _ => 4096,
+        }
+    }
+
+    /// Largest display size (width, height) in pixels
+    pub fn display_size(&self) -> (usize, usize) {
+        if self.schip() {
+            (HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT)
+        } else {
+            (DISPLAY_WIDTH, DISPLAY_HEIGHT)
+        }
+    }
+
+    /// The platform has the SUPER-CHIP instructions
+    pub fn schip(&self) -> bool {
+        matches!(
+            self,
+            Platform::SchipLegacy | Platform::SchipModern | Platform::XoChip
+        )
+    }
+
+    /// The instruction is available on the platform
+    pub fn supports(&self, instr: &Instruction) -> bool {
+        match instr {
+            Scd(_) | Scr | Scl | Exit | Low | High | Hfont(_) | Srpl(_) | Lrpl(_) => self.schip(),
+            Scu(_) | Ldil | Sregr(..) | Lregr(..) | Plane(_) | Audio | Pitch(_) => {
+                *self == Platform::XoChip
+            }
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Platform::NAMES[*self as usize])
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vip" => Ok(Platform::CosmacVip),
+            "chip48" => Ok(Platform::Chip48),
+            "schip-legacy" => Ok(Platform::SchipLegacy),
+            "schip-modern" => Ok(Platform::SchipModern),
+            "xo-chip" => Ok(Platform::XoChip),
+            _ => Result::Err(format!("unknown platform {s}")),
+        }
+    }
 }
 
 impl Chip8 {
-    pub fn new(program: Vec<u8>, quirks: Quirks) -> Self {
+    /// Create a Chip8 with the program loaded at load_address, which is also where execution starts
+    pub fn load(
+        program: &[u8],
+        load_address: usize,
+        platform: Platform,
+        quirks: Quirks,
+    ) -> Result<Self, LoadError> {
+        let memory_size = platform.memory_size();
+
+        if !(RESERVED_SIZE..memory_size).contains(&load_address) {
+            return Result::Err(LoadError::InvalidLoadAddress {
+                address: load_address,
+                platform,
+            });
+        }
+
+        if load_address + program.len() > memory_size {
+            return Result::Err(LoadError::ProgramTooLarge {
+                size: program.len(),
+                available: memory_size - load_address,
+                platform,
+            });
+        }
+
         let mut memory: [u8; MEMORY_SIZE] = [0; MEMORY_SIZE];
 
         memory[..FONTS_SIZE].copy_from_slice(&FONTS); // Load fonts from address 0x0000
-        memory[PROGRAM_START..(PROGRAM_START + program.len())].copy_from_slice(&program); // Load program at PROGRAM_START
+        memory[BIG_FONTS_START..(BIG_FONTS_START + BIG_FONTS_SIZE)].copy_from_slice(&BIG_FONTS); // Load big fonts right after
+        memory[load_address..(load_address + program.len())].copy_from_slice(program); // Load program at load_address
 
-        Chip8 {
+        Ok(Chip8 {
             memory,
             registers: [0; NUMBER_OF_REGISTERS],
             dt: 0,
             st: 0,
             i: 0,
-            pc: PROGRAM_START,
+            pc: load_address,
             sp: 0,
             stack: [0; STACK_SIZE],
-            display: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            flags: [0; NUMBER_OF_FLAGS],
+            display: [[0; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT],
+            plane: 1,
+            hires: false,
             display_update: false,
             keyboard: [false; KEYBOARD_SIZE],
+            platform,
             quirks,
+            pattern: None,
+            pitch: 64,
+            rng: Box::new(SplitMix64::from_entropy()),
+            key_wait: None,
+        })
+    }
+
+    /// Width of the display in the current resolution mode
+    pub fn display_width(&self) -> usize {
+        if self.hires {
+            HIRES_DISPLAY_WIDTH
+        } else {
+            DISPLAY_WIDTH
         }
     }
 
+    /// Height of the display in the current resolution mode
+    pub fn display_height(&self) -> usize {
+        if self.hires {
+            HIRES_DISPLAY_HEIGHT
+        } else {
+            DISPLAY_HEIGHT
+        }
+    }
+
+    /// Playback rate of the audio pattern in bits per second (XO-CHIP)
+    pub fn pattern_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    /// Memory
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// General purpose registers V0-VF
+    pub fn registers(&self) -> &[u8; NUMBER_OF_REGISTERS] {
+        &self.registers
+    }
+
+    /// Index register
+    pub fn i(&self) -> usize {
+        self.i
+    }
+
+    /// Program counter
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Stack pointer
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    /// Stack. Only the entries below the stack pointer are in use
+    pub fn stack(&self) -> &[usize; STACK_SIZE] {
+        &self.stack
+    }
+
+    /// Bitplanes selected for drawing (XO-CHIP)
+    pub fn plane(&self) -> u8 {
+        self.plane
+    }
+
+    /// Replace the source of random numbers, e.g. with a seeded one for reproducible runs
+    pub fn set_rng(&mut self, rng: Box<dyn RandomSource>) {
+        self.rng = rng;
+    }
+
+    /// Memory available on the platform for modification, e.g. by a debugger
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory[..self.platform.memory_size()]
+    }
+
+    /// General purpose registers V0-VF for modification
+    pub fn registers_mut(&mut self) -> &mut [u8; NUMBER_OF_REGISTERS] {
+        &mut self.registers
+    }
+
+    /// Set the index register
+    pub fn set_i(&mut self, i: usize) {
+        self.i = i;
+    }
+
+    /// Set the program counter
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// Set the stack pointer. It is limited to the size of the stack
+    pub fn set_sp(&mut self, sp: usize) {
+        self.sp = sp.min(STACK_SIZE);
+    }
+
+    /// The full state of the Chip8 in a versioned binary format, little endian
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        let quirks = [
+            self.quirks.vf_reset,
+            self.quirks.memory,
+            self.quirks.display_wait,
+            self.quirks.clipping,
+            self.quirks.shifting,
+            self.quirks.jumping,
+            self.quirks.key_press,
+        ];
+
+        state.extend_from_slice(STATE_MAGIC);
+        state.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        state.push(self.platform as u8);
+        state.extend(quirks.iter().map(|q| *q as u8));
+        state.extend_from_slice(&self.memory[..self.platform.memory_size()]);
+        state.extend_from_slice(&self.registers);
+        assert!(self.i <= 0xFFFF, "I {:X} doesn't fit in 16 bits", self.i);
+        state.extend_from_slice(&(self.i as u16).to_le_bytes());
+        state.extend_from_slice(&(self.pc as u16).to_le_bytes());
+        state.push(self.sp as u8);
+        for address in self.stack {
+            state.extend_from_slice(&(address as u16).to_le_bytes());
+        }
+        state.extend_from_slice(&self.flags);
+        state.push(self.dt);
+        state.push(self.st);
+        for row in &self.display {
+            state.extend_from_slice(row);
+        }
+        state.push(self.plane);
+        state.push(self.hires as u8);
+        state.extend(self.keyboard.iter().map(|k| *k as u8));
+        state.push(self.pattern.is_some() as u8);
+        state.extend_from_slice(&self.pattern.unwrap_or_default());
+        state.push(self.pitch);
+        state.extend_from_slice(&self.rng.state().to_le_bytes());
+        state.push(self.key_wait.unwrap_or(NO_KEY));
+
+        state
+    }
+
+    /// Restore the full state from a save state. The Chip8 is unchanged if the save state can't be loaded
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader { state };
+
+        if r.bytes(STATE_MAGIC.len())? != STATE_MAGIC {
+            return Result::Err(StateError::InvalidFormat);
+        }
+        let version = r.u16()?;
+        if !(STATE_MIN_VERSION..=STATE_VERSION).contains(&version) {
+            return Result::Err(StateError::UnsupportedVersion(version));
+        }
+
+        let index = r.u8()?;
+        let platform: Platform = Platform::NAMES
+            .get(index as usize)
+            .and_then(|name| name.parse().ok())
+            .ok_or(StateError::InvalidPlatform(index))?;
+        let quirks = Quirks {
+            vf_reset: r.bool()?,
+            memory: r.bool()?,
+            display_wait: r.bool()?,
+            clipping: r.bool()?,
+            shifting: r.bool()?,
+            jumping: r.bool()?,
+            key_press: version >= 3 && r.bool()?,
+        };
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..platform.memory_size()].copy_from_slice(r.bytes(platform.memory_size())?);
+        let mut registers = [0; NUMBER_OF_REGISTERS];
+        registers.copy_from_slice(r.bytes(NUMBER_OF_REGISTERS)?);
+        let i = r.u16()? as usize;
+        let pc = r.u16()? as usize;
+        let sp = (r.u8()? as usize).min(STACK_SIZE);
+        let mut stack = [0; STACK_SIZE];
+        for address in stack.iter_mut() {
+            *address = r.u16()? as usize;
+        }
+        let mut flags = [0; NUMBER_OF_FLAGS];
+        flags.copy_from_slice(r.bytes(NUMBER_OF_FLAGS)?);
+        let dt = r.u8()?;
+        let st = r.u8()?;
+        let mut display = [[0; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT];
+        for row in display.iter_mut() {
+            row.copy_from_slice(r.bytes(HIRES_DISPLAY_WIDTH)?);
+        }
+        // Pixels and the selected planes are masks of the bitplanes
+        let planes = (1u8 << NUMBER_OF_PLANES) - 1;
+        if display.iter().flatten().any(|pixel| pixel & !planes != 0) {
+            return Result::Err(StateError::InvalidFormat);
+        }
+        let plane = r.u8()?;
+        if plane & !planes != 0 {
+            return Result::Err(StateError::InvalidFormat);
+        }
+        let hires = r.bool()?;
+        let mut keyboard = [false; KEYBOARD_SIZE];
+        for key in keyboard.iter_mut() {
+            *key = r.bool()?;
+        }
+        let has_pattern = r.bool()?;
+        let mut pattern = [0; AUDIO_PATTERN_SIZE];
+        pattern.copy_from_slice(r.bytes(AUDIO_PATTERN_SIZE)?);
+        let pitch = r.u8()?;
+        // Older save states keep the current state of the random number generator
+        let rng_state = if version >= 2 {
+            r.u64()?
+        } else {
+            self.rng.state()
+        };
+
+        let key_wait = match version {
+            3.. => match r.u8()? {
+                NO_KEY => None,
+                key if (key as usize) < KEYBOARD_SIZE => Some(key),
+                _ => return Result::Err(StateError::InvalidFormat),
+            },
+            _ => None,
+        };
+
+        let mut rng = std::mem::replace(&mut self.rng, Box::new(SplitMix64::new(0)));
+        rng.set_state(rng_state);
+
+        *self = Chip8 {
+            memory,
+            registers,
+            dt,
+            st,
+            i,
+            pc,
+            sp,
+            stack,
+            flags,
+            display,
+            plane,
+            hires,
+            display_update: true,
+            keyboard,
+            platform,
+            quirks,
+            pattern: has_pattern.then_some(pattern),
+            pitch,
+            rng,
+            key_wait,
+        };
+
+        Ok(())
+    }
+
     /// Fetch, decode and execute one instruction
-    pub fn step(&mut self) {
-        let instr = self.fetch();
-        let instr = Chip8::decode(instr);
-        self.execute(instr);
+    pub fn step(&mut self) -> Result<StepOutcome, Chip8Error> {
+        let pc = self.pc;
+        let opcode = self.fetch().map_err(|cause| Chip8Error {
+            pc,
+            opcode: 0,
+            cause,
+        })?;
+        let instr = Chip8::decode(opcode, self.platform);
+        self.execute(instr)
+            .map_err(|cause| Chip8Error { pc, opcode, cause })
     }
 
     /// Fetch one instruction from memory at current program counter
-    fn fetch(&self) -> u16 {
-        (self.memory[self.pc] as u16) << 8 | (self.memory[1 + self.pc] as u16)
+    pub fn fetch(&self) -> Result<u16, ErrorCause> {
+        let range = self.memory_range(self.pc, 2)?;
+        Ok((self.memory[range.start] as u16) << 8 | (self.memory[range.start + 1] as u16))
+    }
+
+    /// Range of len bytes of memory starting at address, if within the platform memory size
+    fn memory_range(&self, address: usize, len: usize) -> Result<Range<usize>, ErrorCause> {
+        if address + len <= self.platform.memory_size() {
+            Ok(address..(address + len))
+        } else {
+            Result::Err(ErrorCause::MemoryOutOfBounds(address))
+        }
     }
 
-    /// Decode an instruction
-    fn decode(instr: u16) -> Instruction {
+    /// Key pressed state for the key number in a register
+    fn key(&self, x: usize) -> Result<bool, ErrorCause> {
+        let key = self.registers[x];
+        self.keyboard
+            .get(key as usize)
+            .copied()
+            .ok_or(ErrorCause::InvalidKey(key))
+    }
+
+    /// Decode an instruction for a platform. Instructions not available on the platform are decoded as SYS or errors
+    pub fn decode(instr: u16, platform: Platform) -> Instruction {
         let i = ((instr & 0xF000) >> 12) as u8;
         let x = ((instr & 0x0F00) >> 8) as usize;
         let y = ((instr & 0x00F0) >> 4) as usize;
@@ -126,17 +785,29 @@ impl Chip8 {
         let nn = (instr & 0x00FF) as u8;
         let nnn = (instr & 0x0FFF) as usize;
 
-        match i {
+        let decoded = match i {
             0 => match nnn {
+                0x0C0..=0x0CF => Scd(n),
+                0x0D0..=0x0DF => Scu(n),
                 0x0E0 => Cls,
                 0x0EE => Ret,
+                0x0FB => Scr,
+                0x0FC => Scl,
+                0x0FD => Exit,
+                0x0FE => Low,
+                0x0FF => High,
                 _ => Sys,
             },
             1 => Jmp(nnn),
             2 => Call(nnn),
             3 => Skeb(x, nn),
             4 => Skneb(x, nn),
-            5 => Ske(x, y),
+            5 => match n {
+                0 => Ske(x, y),
+                2 => Sregr(x, y),
+                3 => Lregr(x, y),
+                _ => Err,
+            },
             6 => Ldb(x, nn),
             7 => Addb(x, nn),
             8 => match n {
@@ -162,43 +833,90 @@ impl Chip8 {
                 _ => Err,
             },
             0xF => match nn {
+                0x00 if x == 0 => Ldil,
+                0x01 => Plane(x as u8),
+                0x02 if x == 0 => Audio,
                 0x07 => Ldft(x),
                 0x0A => Ldkp(x),
                 0x15 => Ldtt(x),
                 0x18 => Ldst(x),
                 0x1E => Addi(x),
                 0x29 => Font(x),
+                0x30 => Hfont(x),
                 0x33 => Bcd(x),
+                0x3A => Pitch(x),
                 0x55 => Sreg(x),
                 0x65 => Lreg(x),
+                0x75 => Srpl(x),
+                0x85 => Lrpl(x),
                 _ => Err,
             },
             _ => Err,
+        };
+
+        if platform.supports(&decoded) {
+            decoded
+        } else if i == 0 {
+            Sys
+        } else {
+            Err
         }
     }
 
     /// Execute one instruction
-    fn execute(&mut self, instr: Instruction) {
-        #[cfg(debug_assertions)]
-        eprintln!(
-            "pc: {:04X} instr: {:04X?} regs: {:02X?}",
-            self.pc, instr, self.registers
-        );
+    fn execute(&mut self, instr: Instruction) -> Result<StepOutcome, ErrorCause> {
         // Increment program counter before as a default for most instructions
         self.pc += 2;
+        let mut outcome = StepOutcome::Executed;
 
         match instr {
             Sys => {}
             Cls => {
-                self.display = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+                for row in self.display.iter_mut() {
+                    for pixel in row.iter_mut() {
+                        *pixel &= !self.plane;
+                    }
+                }
+                self.display_update = true;
+            }
+            Scd(n) => {
+                self.scroll(0, n as isize);
+            }
+            Scu(n) => {
+                self.scroll(0, -(n as isize));
+            }
+            Scr => {
+                self.scroll(4, 0);
+            }
+            Scl => {
+                self.scroll(-4, 0);
+            }
+            Exit => {
+                self.pc -= 2; // Stay on the exit instruction
+                outcome = StepOutcome::Exit;
+            }
+            Low => {
+                self.hires = false;
+                self.display = [[0; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT];
+                self.display_update = true;
+            }
+            High => {
+                self.hires = true;
+                self.display = [[0; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT];
                 self.display_update = true;
             }
             Call(nnn) => {
+                if self.sp == STACK_SIZE {
+                    return Result::Err(ErrorCause::StackOverflow);
+                }
                 self.stack[self.sp] = self.pc;
                 self.sp += 1;
                 self.pc = nnn;
             }
             Ret => {
+                if self.sp == 0 {
+                    return Result::Err(ErrorCause::StackUnderflow);
+                }
                 self.sp -= 1;
                 self.pc = self.stack[self.sp];
             }
@@ -207,22 +925,22 @@ impl Chip8 {
             }
             Skeb(x, nn) => {
                 if self.registers[x] == nn {
-                    self.pc += 2;
+                    self.skip();
                 }
             }
             Skneb(x, nn) => {
                 if self.registers[x] != nn {
-                    self.pc += 2;
+                    self.skip();
                 }
             }
             Ske(x, y) => {
                 if self.registers[x] == self.registers[y] {
-                    self.pc += 2;
+                    self.skip();
                 }
             }
             Skne(x, y) => {
                 if self.registers[x] != self.registers[y] {
-                    self.pc += 2;
+                    self.skip();
                 }
             }
             Ldb(x, nn) => {
@@ -304,49 +1022,83 @@ impl Chip8 {
                 }
             }
             Rnd(x, nn) => {
-                self.registers[x] = rand::random::<u8>() & nn;
+                self.registers[x] = self.rng.next_u8() & nn;
             }
             Draw(x, y, n) => {
-                let px = (self.registers[x] % (DISPLAY_WIDTH as u8)) as usize;
-                let py = (self.registers[y] % (DISPLAY_HEIGHT as u8)) as usize;
-                let sprite = &self.memory[self.i..(self.i + n as usize)];
-                self.registers[0xF] = 0;
-
-                for (dy, byte) in sprite.iter().enumerate() {
-                    if self.quirks.clipping && (py + dy) >= DISPLAY_HEIGHT {
-                        break;
+                let width = self.display_width();
+                let height = self.display_height();
+                let px = self.registers[x] as usize % width;
+                let py = self.registers[y] as usize % height;
+                // Height 0 means a 16x16 sprite with two bytes per row (SUPER-CHIP)
+                let (rows, cols) = if n == 0 && self.platform.schip() {
+                    (16, 16)
+                } else {
+                    (n as usize, 8)
+                };
+                let bytes_per_row = cols / 8;
+                let sprite_size = rows * bytes_per_row;
+                // With several planes selected, the sprite data for each plane follow each other in memory (XO-CHIP)
+                let mut address = self.i;
+                // SUPER-CHIP 1.1 sets VF to the number of rows that collided or were clipped at the bottom in high resolution
+                let count_rows = self.platform == Platform::SchipLegacy && self.hires;
+                let mut rows_hit = 0;
+
+                for mask in (0..NUMBER_OF_PLANES).map(|p| 1u8 << p) {
+                    if self.plane & mask == 0 {
+                        continue;
                     }
 
-                    for dx in 0..8 {
-                        if self.quirks.clipping && (px + dx) >= DISPLAY_WIDTH {
+                    let sprite = &self.memory[self.memory_range(address, sprite_size)?];
+                    address += sprite_size;
+
+                    for (dy, row) in sprite.chunks(bytes_per_row).enumerate() {
+                        if self.quirks.clipping && (py + dy) >= height {
+                            if count_rows {
+                                rows_hit += rows - dy;
+                            }
                             break;
                         }
 
-                        let old =
-                            self.display[(py + dy) % DISPLAY_HEIGHT][(px + dx) % DISPLAY_WIDTH];
-                        let mut new = ((byte >> (7 - dx)) & 1) == 1;
+                        // Left align the sprite row in 16 bits
+                        let bits = row
+                            .iter()
+                            .enumerate()
+                            .fold(0u16, |acc, (b, byte)| acc | (*byte as u16) << (8 - 8 * b));
+                        let mut hit = false;
 
-                        if new {
-                            if old {
-                                new = false;
-                                self.registers[0xF] = 1;
+                        for dx in 0..cols {
+                            if self.quirks.clipping && (px + dx) >= width {
+                                break;
                             }
 
-                            self.display[(py + dy) % DISPLAY_HEIGHT][(px + dx) % DISPLAY_WIDTH] =
-                                new;
-                            self.display_update = true;
+                            if ((bits >> (15 - dx)) & 1) == 1 {
+                                let pixel =
+                                    &mut self.display[(py + dy) % height][(px + dx) % width];
+
+                                hit |= *pixel & mask != 0;
+                                *pixel ^= mask;
+                                self.display_update = true;
+                            }
                         }
+
+                        rows_hit += hit as usize;
                     }
                 }
+
+                self.registers[0xF] = if count_rows {
+                    rows_hit as u8
+                } else {
+                    (rows_hit > 0) as u8
+                };
             }
             Skp(x) => {
-                if self.keyboard[self.registers[x] as usize] {
-                    self.pc += 2;
+                if self.key(x)? {
+                    self.skip();
                 }
             }
             Sknp(x) => {
-                if !self.keyboard[self.registers[x] as usize] {
-                    self.pc += 2;
+                if !self.key(x)? {
+                    self.skip();
                 }
             }
             Ldft(x) => {
@@ -360,66 +1112,167 @@ impl Chip8 {
             }
             Ldkp(x) => {
                 let mut wait = true;
-                for (key, pressed) in self.keyboard.iter().enumerate() {
-                    if *pressed {
+                let held = self.keyboard.iter().position(|pressed| *pressed);
+
+                if self.quirks.key_press {
+                    if let Some(key) = held {
                         self.registers[x] = key as u8;
                         wait = false;
                         self.keyboard[key] = false;
-                        break;
+                    }
+                } else {
+                    // Wait for a key to be pressed, then sound the buzzer until it is released like the COSMAC VIP
+                    match self.key_wait {
+                        None => self.key_wait = held.map(|key| key as u8),
+                        Some(key) if self.keyboard[key as usize] => self.st = self.st.max(1),
+                        Some(key) => {
+                            self.registers[x] = key;
+                            self.key_wait = None;
+                            wait = false;
+                        }
                     }
                 }
 
                 if wait {
                     self.pc -= 2;
+                    outcome = StepOutcome::WaitingForKey;
                 }
             }
+            // I is a 16 bit register
             Addi(x) => {
-                self.i += self.registers[x] as usize;
+                self.i = (self.i + self.registers[x] as usize) & 0xFFFF;
             }
             Font(x) => {
-                self.i = (self.registers[x] * 5) as usize;
+                self.i = (self.registers[x] & 0xF) as usize * 5;
+            }
+            Hfont(x) => {
+                self.i = BIG_FONTS_START + (self.registers[x] & 0xF) as usize * 10;
             }
             Bcd(x) => {
-                let val = self.registers[x] as u16;
-                self.memory[self.i] = (val % 1000 / 100) as u8;
-                self.memory[self.i + 1] = (val % 100 / 10) as u8;
-                self.memory[self.i + 2] = (val % 10) as u8;
+                let val = self.registers[x];
+                let range = self.memory_range(self.i, 3)?;
+                self.memory[range].copy_from_slice(&[val / 100, val % 100 / 10, val % 10]);
             }
             Sreg(x) => {
-                for r in 0..x + 1 {
-                    self.memory[self.i + r] = self.registers[r];
-                }
+                let range = self.memory_range(self.i, x + 1)?;
+                self.memory[range].copy_from_slice(&self.registers[..=x]);
 
                 if self.quirks.memory {
                     self.i += x + 1;
                 }
             }
             Lreg(x) => {
-                for r in 0..x + 1 {
-                    self.registers[r] = self.memory[self.i + r];
-                }
+                let range = self.memory_range(self.i, x + 1)?;
+                self.registers[..=x].copy_from_slice(&self.memory[range]);
 
                 if self.quirks.memory {
                     self.i += x + 1;
                 }
             }
+            Ldil => {
+                self.i = self.fetch()? as usize;
+                self.pc += 2;
+            }
+            Sregr(x, y) => {
+                self.memory_range(self.i, x.abs_diff(y) + 1)?;
+                for offset in 0..=x.abs_diff(y) {
+                    let r = if x <= y { x + offset } else { x - offset };
+                    self.memory[self.i + offset] = self.registers[r];
+                }
+            }
+            Lregr(x, y) => {
+                self.memory_range(self.i, x.abs_diff(y) + 1)?;
+                for offset in 0..=x.abs_diff(y) {
+                    let r = if x <= y { x + offset } else { x - offset };
+                    self.registers[r] = self.memory[self.i + offset];
+                }
+            }
+            Plane(n) => {
+                self.plane = n & 0x3;
+            }
+            Audio => {
+                let mut pattern = [0; AUDIO_PATTERN_SIZE];
+                pattern
+                    .copy_from_slice(&self.memory[self.memory_range(self.i, AUDIO_PATTERN_SIZE)?]);
+                self.pattern = Some(pattern);
+            }
+            Pitch(x) => {
+                self.pitch = self.registers[x];
+            }
+            Srpl(x) => {
+                for r in 0..(x + 1).min(NUMBER_OF_FLAGS) {
+                    self.flags[r] = self.registers[r];
+                }
+            }
+            Lrpl(x) => {
+                for r in 0..(x + 1).min(NUMBER_OF_FLAGS) {
+                    self.registers[r] = self.flags[r];
+                }
+            }
             Err => {
-                panic!("Unimplemented instruction {:04X?}!", instr);
+                return Result::Err(ErrorCause::UnknownInstruction);
             }
         }
+
+        Ok(outcome)
+    }
+
+    /// Skip the next instruction. The long load F000 nnnn is two words long (XO-CHIP)
+    fn skip(&mut self) {
+        self.pc += if self.platform == Platform::XoChip && self.fetch() == Ok(0xF000) {
+            4
+        } else {
+            2
+        };
+    }
+
+    /// Scroll the selected planes of the display dx pixels right and dy pixels down. Negative values scroll left and up
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let old = self.display;
+        let width = self.display_width() as isize;
+        let height = self.display_height() as isize;
+
+        for y in 0..height {
+            for x in 0..width {
+                let (sx, sy) = (x - dx, y - dy);
+                let bits = if (0..width).contains(&sx) && (0..height).contains(&sy) {
+                    old[sy as usize][sx as usize] & self.plane
+                } else {
+                    0
+                };
+                let pixel = &mut self.display[y as usize][x as usize];
+                *pixel = (*pixel & !self.plane) | bits;
+            }
+        }
+
+        self.display_update = true;
     }
 }
 
 /// Instructions as enum in an effort to make instruction decoding and execution clearer.
 /// Match expressions and doc-comments will make coding easier.
-#[derive(Debug)]
-enum Instruction {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
     /// 0nnn - SYS addr. Jump to machine code at address (unused in practice).
     Sys,
     /// 00E0 - CLS. Clear the screen.
     Cls,
     /// 00EE - RET. Return from subroutine.
     Ret,
+    /// 00Cn - SCD n. Scroll display down n pixels (SUPER-CHIP).
+    Scd(u8),
+    /// 00Dn - SCU n. Scroll display up n pixels (XO-CHIP).
+    Scu(u8),
+    /// 00FB - SCR. Scroll display right 4 pixels (SUPER-CHIP).
+    Scr,
+    /// 00FC - SCL. Scroll display left 4 pixels (SUPER-CHIP).
+    Scl,
+    /// 00FD - EXIT. Exit the interpreter (SUPER-CHIP).
+    Exit,
+    /// 00FE - LOW. Disable high resolution mode (SUPER-CHIP).
+    Low,
+    /// 00FF - HIGH. Enable high resolution mode (SUPER-CHIP).
+    High,
     /// 1nnn - JMP addr. Jump to address.
     Jmp(usize),
     /// 2nnn - CALL addr. Call subroutine at address.
@@ -450,17 +1303,17 @@ enum Instruction {
     Sub(usize, usize),
     /// 8xy7 - SUBR Vx, Vy. Set VX = VY - VX with borrow status in VF (not borrow means set). Remember that VX can be the same as VF.
     Subr(usize, usize),
-    /// 8xy6 - SHR Vx. Shift VX right with bit 0 before shift in VF. Remember that VX can be the same as VF. Instruction with quirks.
+    /// 8xy6 - SHR Vx, Vy. Shift VX right with bit 0 before shift in VF. Remember that VX can be the same as VF. Instruction with quirks.
     Shr(usize, usize),
-    /// 8xyE - SHL Vx. Shift VX left with bit 7 before shift in VF. Remember that VX can be the same as VF. Instruction with quirks.
+    /// 8xyE - SHL Vx, Vy. Shift VX left with bit 7 before shift in VF. Remember that VX can be the same as VF. Instruction with quirks.
     Shl(usize, usize),
-    /// Annn - LD I. Set index register to nnn.
+    /// Annn - LDI addr. Set index register to nnn.
     Ldi(usize),
     /// Bnnn - JMPZ addr. Jump to nnn + V0.
     Jmpz(usize),
     /// Cxkk - RND Vx, byte. Set VX to (random number AND byte).
     Rnd(usize, u8),
-    /// Dxyn - DRAW Vx, Vy, n. Draw sprite of height n from memory location I at location VX, VY using XOR and collision status in VF (if any bit is flipped from 1 to 0).
+    /// Dxyn - DRAW Vx, Vy, n. Draw sprite of height n from memory location I at location VX, VY using XOR and collision status in VF (if any bit is flipped from 1 to 0). Dxy0 draws a 16x16 sprite (SUPER-CHIP). SUPER-CHIP 1.1 sets VF to the number of rows that collided or were clipped in high resolution.
     Draw(usize, usize, u8),
     /// Ex9E - SKP Vx. Skip next instruction if key number in VX is pressed.
     Skp(usize),
@@ -478,12 +1331,424 @@ enum Instruction {
     Addi(usize),
     /// Fx29 - FONT Vx. Load I with font for key num in VX.
     Font(usize),
+    /// Fx30 - HFONT Vx. Load I with big font for key num in VX (SUPER-CHIP).
+    Hfont(usize),
     /// Fx33 - BCD Vx. Store BCD value of VX in I, I+1 and I+2.
     Bcd(usize),
     /// Fx55 - SREG Vx. Store registers V0 to VX in memory starting at I.
     Sreg(usize),
     /// Fx65 - LREG Vx. Load register V0 to VX from memory starting at I.
     Lreg(usize),
+    /// F000 nnnn - LDIL addr. Load I with the 16-bit address in the next word (XO-CHIP).
+    Ldil,
+    /// 5xy2 - SREGR Vx, Vy. Store registers VX to VY in memory starting at I without changing I (XO-CHIP).
+    Sregr(usize, usize),
+    /// 5xy3 - LREGR Vx, Vy. Load registers VX to VY from memory starting at I without changing I (XO-CHIP).
+    Lregr(usize, usize),
+    /// Fn01 - PLANE n. Select bitplanes n (0-3) for drawing, clearing and scrolling (XO-CHIP).
+    Plane(u8),
+    /// F002 - AUDIO. Load the 16-byte audio pattern buffer from memory starting at I (XO-CHIP).
+    Audio,
+    /// Fx3A - PITCH Vx. Set the audio pattern playback rate from VX (XO-CHIP).
+    Pitch(usize),
+    /// Fx75 - SRPL Vx. Store registers V0 to VX in RPL user flags (SUPER-CHIP, up to VF in XO-CHIP).
+    Srpl(usize),
+    /// Fx85 - LRPL Vx. Load registers V0 to VX from RPL user flags (SUPER-CHIP).
+    Lrpl(usize),
     /// It's not an instruction. Something's wrong.
     Err,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::SplitMix64;
+
+    /// Offset of the key_press quirk in a save state
+    const KEY_PRESS_OFFSET: usize = 13;
+
+    /// A Chip8 with some state, waiting for key 7 to be released
+    fn chip8() -> Chip8 {
+        let program = [0x60, 0x05, 0xA2, 0x34, 0xC2, 0xFF, 0xF1, 0x0A];
+        let platform = Platform::SchipModern;
+        let mut chip8 = Chip8::load(&program, 0x200, platform, platform.quirks()).unwrap();
+        chip8.set_rng(Box::new(SplitMix64::new(42)));
+        chip8.keyboard[7] = true;
+        for _ in 0..4 {
+            chip8.step().unwrap();
+        }
+        chip8
+    }
+
+    /// A save state of the current version converted to an older version
+    fn downgrade(mut state: Vec<u8>, version: u16) -> Vec<u8> {
+        state[4..6].copy_from_slice(&version.to_le_bytes());
+        // The key waited for (v3), the random number generator (v2) and the key_press quirk (v3)
+        state.pop();
+        if version < 2 {
+            state.truncate(state.len() - 8);
+        }
+        state.remove(KEY_PRESS_OFFSET);
+        state
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let chip8 = chip8();
+        let state = chip8.save_state();
+
+        let mut loaded = Chip8::load(
+            &[0x00, 0xE0],
+            0x200,
+            Platform::XoChip,
+            Platform::XoChip.quirks(),
+        )
+        .unwrap();
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.save_state(), state);
+        assert_eq!(loaded.key_wait, Some(7));
+        assert_eq!(loaded.registers()[0], 5);
+        assert_eq!(loaded.i(), 0x234);
+    }
+
+    #[test]
+    fn older_state_versions_load() {
+        let chip8 = chip8();
+        let state = chip8.save_state();
+
+        for version in STATE_MIN_VERSION..STATE_VERSION {
+            let mut loaded = Chip8::load(
+                &[0x00, 0xE0],
+                0x200,
+                Platform::XoChip,
+                Platform::XoChip.quirks(),
+            )
+            .unwrap();
+            loaded.set_rng(Box::new(SplitMix64::new(1)));
+            let rng = loaded.rng.state();
+            loaded
+                .load_state(&downgrade(state.clone(), version))
+                .unwrap();
+
+            // The fields the older version lacks are left at their defaults or the current state
+            assert_eq!(loaded.key_wait, None);
+            assert!(!loaded.quirks.key_press);
+            if version < 2 {
+                assert_eq!(loaded.rng.state(), rng);
+            } else {
+                assert_eq!(loaded.rng.state(), chip8.rng.state());
+            }
+            let mut expected = state.clone();
+            *expected.last_mut().unwrap() = NO_KEY;
+            if version < 2 {
+                let rng_offset = expected.len() - 9;
+                expected[rng_offset..rng_offset + 8].copy_from_slice(&rng.to_le_bytes());
+            }
+            assert_eq!(loaded.save_state(), expected);
+        }
+    }
+
+    #[test]
+    fn invalid_states_are_rejected() {
+        let state = chip8().save_state();
+        let mut chip8 = chip8();
+        let check = |chip8: &mut Chip8, state: &[u8], error: StateError| {
+            let before = chip8.save_state();
+            assert_eq!(chip8.load_state(state), Result::Err(error));
+            assert_eq!(chip8.save_state(), before);
+        };
+
+        for len in [0, 3, 5, 100, state.len() - 1] {
+            check(&mut chip8, &state[..len], StateError::Truncated);
+        }
+
+        let mut corrupt = state.clone();
+        corrupt[0] = b'X';
+        check(&mut chip8, &corrupt, StateError::InvalidFormat);
+
+        let mut corrupt = state.clone();
+        corrupt[4..6].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        check(
+            &mut chip8,
+            &corrupt,
+            StateError::UnsupportedVersion(STATE_VERSION + 1),
+        );
+
+        let mut corrupt = state.clone();
+        corrupt[6] = 9;
+        check(&mut chip8, &corrupt, StateError::InvalidPlatform(9));
+
+        let mut corrupt = state.clone();
+        *corrupt.last_mut().unwrap() = KEYBOARD_SIZE as u8;
+        check(&mut chip8, &corrupt, StateError::InvalidFormat);
+
+        // A pixel and the selected planes outside the two bitplanes
+        let display_offset = state.len()
+            - (HIRES_DISPLAY_WIDTH * HIRES_DISPLAY_HEIGHT + 2 + 16 + 1 + 16 + 1 + 8 + 1);
+        let mut corrupt = state.clone();
+        corrupt[display_offset + 5] = 4;
+        check(&mut chip8, &corrupt, StateError::InvalidFormat);
+
+        let mut corrupt = state.clone();
+        corrupt[display_offset + HIRES_DISPLAY_WIDTH * HIRES_DISPLAY_HEIGHT] = 4;
+        check(&mut chip8, &corrupt, StateError::InvalidFormat);
+    }
+
+    /// A Chip8 running FX0A with X = 3
+    fn waiting(key_press: bool) -> Chip8 {
+        let platform = Platform::CosmacVip;
+        let mut quirks = platform.quirks();
+        quirks.key_press = key_press;
+        Chip8::load(&[0xF3, 0x0A], 0x200, platform, quirks).unwrap()
+    }
+
+    #[test]
+    fn key_wait_waits_for_press_and_release() {
+        let mut chip8 = waiting(false);
+
+        // No key
+        assert_eq!(chip8.step().unwrap(), StepOutcome::WaitingForKey);
+        assert_eq!(chip8.pc(), 0x200);
+
+        // Pressed and held
+        chip8.keyboard[0xA] = true;
+        for _ in 0..3 {
+            assert_eq!(chip8.step().unwrap(), StepOutcome::WaitingForKey);
+            assert_eq!(chip8.pc(), 0x200);
+        }
+        assert_eq!(chip8.key_wait, Some(0xA));
+        assert!(chip8.st > 0);
+
+        // Released
+        chip8.keyboard[0xA] = false;
+        assert_eq!(chip8.step().unwrap(), StepOutcome::Executed);
+        assert_eq!(chip8.pc(), 0x202);
+        assert_eq!(chip8.registers()[3], 0xA);
+        assert_eq!(chip8.key_wait, None);
+    }
+
+    #[test]
+    fn key_press_quirk_takes_the_held_key() {
+        let mut chip8 = waiting(true);
+
+        assert_eq!(chip8.step().unwrap(), StepOutcome::WaitingForKey);
+        assert_eq!(chip8.pc(), 0x200);
+
+        chip8.keyboard[0xA] = true;
+        assert_eq!(chip8.step().unwrap(), StepOutcome::Executed);
+        assert_eq!(chip8.pc(), 0x202);
+        assert_eq!(chip8.registers()[3], 0xA);
+        assert_eq!(chip8.key_wait, None);
+    }
+
+    /// A Chip8 of the platform with the program after steps instructions
+    fn run(platform: Platform, program: &[u8], steps: usize) -> Chip8 {
+        let mut chip8 = Chip8::load(program, 0x200, platform, platform.quirks()).unwrap();
+        for _ in 0..steps {
+            chip8.step().unwrap();
+        }
+        chip8
+    }
+
+    /// Coordinates of the set pixels
+    fn pixels(chip8: &Chip8) -> Vec<(usize, usize, u8)> {
+        let mut pixels = Vec::new();
+        for (y, row) in chip8.display.iter().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                if *pixel != 0 {
+                    pixels.push((x, y, *pixel));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn scrolling() {
+        // HIGH, LDI 0x20C, LDB V0 8, DRAW V0 V0 1, SCD 2, SCR, then a sprite of one pixel
+        let program = [
+            0x00, 0xFF, 0xA2, 0x0C, 0x60, 0x08, 0xD0, 0x01, 0x00, 0xC2, 0x00, 0xFB, 0x80,
+        ];
+
+        let chip8 = run(Platform::SchipModern, &program, 4);
+        assert_eq!(pixels(&chip8), [(8, 8, 1)]);
+        let chip8 = run(Platform::SchipModern, &program, 5);
+        assert_eq!(pixels(&chip8), [(8, 10, 1)]);
+        let chip8 = run(Platform::SchipModern, &program, 6);
+        assert_eq!(pixels(&chip8), [(12, 10, 1)]);
+    }
+
+    #[test]
+    fn large_sprite_collision() {
+        // HIGH, LDI 0x20A, LDB V0 0, DRAW V0 V0 0 twice, then a 16x16 square
+        let mut program = vec![0x00, 0xFF, 0xA2, 0x0A, 0x60, 0x00, 0xD0, 0x00, 0xD0, 0x00];
+        program.extend([0xFF; 32]);
+
+        let chip8 = run(Platform::SchipModern, &program, 4);
+        assert_eq!(pixels(&chip8).len(), 256);
+        assert_eq!(chip8.registers()[0xF], 0);
+        let chip8 = run(Platform::SchipModern, &program, 5);
+        assert!(pixels(&chip8).is_empty());
+        assert_eq!(chip8.registers()[0xF], 1);
+    }
+
+    #[test]
+    fn large_sprite_counts_rows_on_superchip_1_1() {
+        // HIGH, LDI 0x210, LDB V0 0, DRAW V0 V0 0, LDB V1 13, DRAW V0 V1 0, LDB V1 56, DRAW V0 V1 0, then a 16x16 square
+        let mut program = vec![
+            0x00, 0xFF, 0xA2, 0x10, 0x60, 0x00, 0xD0, 0x00, 0x61, 0x0D, 0xD0, 0x10, 0x61, 0x38,
+            0xD0, 0x10,
+        ];
+        program.extend([0xFF; 32]);
+
+        // 3 rows overlap
+        let chip8 = run(Platform::SchipLegacy, &program, 6);
+        assert_eq!(chip8.registers()[0xF], 3);
+        let chip8 = run(Platform::SchipModern, &program, 6);
+        assert_eq!(chip8.registers()[0xF], 1);
+
+        // 8 rows are clipped at the bottom
+        let chip8 = run(Platform::SchipLegacy, &program, 8);
+        assert_eq!(chip8.registers()[0xF], 8);
+        let chip8 = run(Platform::SchipModern, &program, 8);
+        assert_eq!(chip8.registers()[0xF], 0);
+    }
+
+    #[test]
+    fn scrolling_moves_the_selected_planes_only() {
+        // PLANE 3, LDI 0x20C, LDB V0 0, DRAW V0 V0 1, PLANE 2, SCD 1, then a pixel in each plane
+        let program = [
+            0xF3, 0x01, 0xA2, 0x0C, 0x60, 0x00, 0xD0, 0x01, 0xF2, 0x01, 0x00, 0xC1, 0x80, 0x80,
+        ];
+
+        let chip8 = run(Platform::XoChip, &program, 4);
+        assert_eq!(pixels(&chip8), [(0, 0, 3)]);
+        let chip8 = run(Platform::XoChip, &program, 6);
+        assert_eq!(pixels(&chip8), [(0, 0, 1), (0, 1, 2)]);
+    }
+
+    #[test]
+    fn drawing_in_two_planes() {
+        // PLANE 3, LDI 0x20A, LDB V0 0, DRAW V0 V0 1 twice, then a row for plane 1 and one for plane 2
+        let program = [
+            0xF3, 0x01, 0xA2, 0x0A, 0x60, 0x00, 0xD0, 0x01, 0xD0, 0x01, 0xF0, 0x3C,
+        ];
+
+        let chip8 = run(Platform::XoChip, &program, 4);
+        assert_eq!(
+            pixels(&chip8),
+            [
+                (0, 0, 1),
+                (1, 0, 1),
+                (2, 0, 3),
+                (3, 0, 3),
+                (4, 0, 2),
+                (5, 0, 2)
+            ]
+        );
+        assert_eq!(chip8.registers()[0xF], 0);
+
+        let chip8 = run(Platform::XoChip, &program, 5);
+        assert!(pixels(&chip8).is_empty());
+        assert_eq!(chip8.registers()[0xF], 1);
+    }
+
+    #[test]
+    fn register_ranges_and_long_load() {
+        // LDB V1 1, LDB V2 2, LDB V3 3, LDIL 0x0300, SREGR V1 V3, LREGR V6 V4
+        let program = [
+            0x61, 0x01, 0x62, 0x02, 0x63, 0x03, 0xF0, 0x00, 0x03, 0x00, 0x51, 0x32, 0x56, 0x43,
+        ];
+
+        let chip8 = run(Platform::XoChip, &program, 6);
+        assert_eq!(chip8.i(), 0x300);
+        assert_eq!(&chip8.memory()[0x300..0x303], [1, 2, 3]);
+        // Loaded in reverse as X > Y, I unchanged
+        assert_eq!(&chip8.registers()[4..7], [3, 2, 1]);
+    }
+
+    /// The error of the program on the COSMAC VIP, stepping until it fails
+    fn error(program: &[u8]) -> Chip8Error {
+        let platform = Platform::CosmacVip;
+        let mut chip8 = Chip8::load(program, 0x200, platform, platform.quirks()).unwrap();
+        loop {
+            if let Result::Err(error) = chip8.step() {
+                return error;
+            }
+        }
+    }
+
+    #[test]
+    fn errors_are_returned() {
+        let cause = |error: Chip8Error| (error.pc, error.opcode, error.cause);
+
+        // CALL 0x200 until the stack is full
+        assert_eq!(
+            cause(error(&[0x22, 0x00])),
+            (0x200, 0x2200, ErrorCause::StackOverflow)
+        );
+        assert_eq!(
+            cause(error(&[0x00, 0xEE])),
+            (0x200, 0x00EE, ErrorCause::StackUnderflow)
+        );
+        // LDI 0xFFF, SREG V1
+        assert_eq!(
+            cause(error(&[0xAF, 0xFF, 0xF1, 0x55])),
+            (0x202, 0xF155, ErrorCause::MemoryOutOfBounds(0xFFF))
+        );
+        // JMP 0xFFF fetches past the end of memory
+        assert_eq!(
+            cause(error(&[0x1F, 0xFF])),
+            (0xFFF, 0, ErrorCause::MemoryOutOfBounds(0xFFF))
+        );
+        // LDB V0 0x10, SKP V0
+        assert_eq!(
+            cause(error(&[0x60, 0x10, 0xE0, 0x9E])),
+            (0x202, 0xE09E, ErrorCause::InvalidKey(0x10))
+        );
+        assert_eq!(
+            cause(error(&[0xFF, 0xFF])),
+            (0x200, 0xFFFF, ErrorCause::UnknownInstruction)
+        );
+    }
+
+    #[test]
+    fn load_checks_size_and_address() {
+        let load = |size: usize, address: usize, platform: Platform| {
+            Chip8::load(&vec![0; size], address, platform, platform.quirks()).map(|_| ())
+        };
+
+        assert_eq!(load(0xE00, 0x200, Platform::CosmacVip), Ok(()));
+        assert_eq!(
+            load(0xE01, 0x200, Platform::CosmacVip),
+            Result::Err(LoadError::ProgramTooLarge {
+                size: 0xE01,
+                available: 0xE00,
+                platform: Platform::CosmacVip
+            })
+        );
+        // XO-CHIP has 64K of memory
+        assert_eq!(load(0xE01, 0x200, Platform::XoChip), Ok(()));
+
+        for address in [0, RESERVED_SIZE - 1, 0x1000] {
+            assert_eq!(
+                load(2, address, Platform::CosmacVip),
+                Result::Err(LoadError::InvalidLoadAddress {
+                    address,
+                    platform: Platform::CosmacVip
+                })
+            );
+        }
+        assert_eq!(load(2, RESERVED_SIZE, Platform::CosmacVip), Ok(()));
+    }
+
+    #[test]
+    fn index_register_wraps_at_16_bits() {
+        // LDIL 0xFFFF, LDB V0 2, ADDI V0
+        let program = [0xF0, 0x00, 0xFF, 0xFF, 0x60, 0x02, 0xF0, 0x1E];
+
+        let chip8 = run(Platform::XoChip, &program, 3);
+        assert_eq!(chip8.i(), 1);
+        chip8.save_state();
+    }
+}
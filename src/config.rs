@@ -0,0 +1,328 @@
+//! The user configuration file with settings, keymap profiles and settings per ROM, read from the XDG config directory
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Deserializer};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    chip8::Platform,
+    keymap::Keymap,
+    timing::{Speed, Timing},
+};
+
+/// Path of the configuration file within the config directory
+const CONFIG_FILE: &str = "chip8rs/config.toml";
+/// Keymap profile used if none is configured
+const DEFAULT_KEYMAP: &str = "qwerty";
+
+/// Contents of the configuration file. Everything is optional
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Settings for all ROMs
+    #[serde(flatten)]
+    pub settings: Settings,
+    /// Keymap profile, built-in or custom
+    pub keymap: Option<String>,
+    /// Custom keymap profiles by name
+    pub keymaps: BTreeMap<String, KeymapProfile>,
+    /// Axis position (of 32767) below which an analog stick of a game controller counts as centered
+    pub deadzone: Option<i16>,
+    /// Settings of ROMs by the SHA-1 of the program in hex
+    pub roms: BTreeMap<String, RomConfig>,
+    /// Keys not known. Flattened settings rule out deny_unknown_fields, so they are collected and rejected on load
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// A custom keymap profile
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeymapProfile {
+    /// Built-in profile to start from. Without one only the given keys are mapped
+    pub base: Option<String>,
+    /// Host keys by Chip8 key as hex digit, e.g. 5 = ["Up", "W"]
+    pub keys: BTreeMap<String, Vec<String>>,
+    /// Game controller inputs by Chip8 key as hex digit, e.g. 5 = ["dpup", "lefty-"]
+    pub buttons: BTreeMap<String, Vec<String>>,
+}
+
+/// Settings of a ROM overriding the global ones
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RomConfig {
+    #[serde(flatten)]
+    pub settings: Settings,
+    /// Keymap profile for the ROM
+    pub keymap: Option<String>,
+    /// Host keys by Chip8 key replacing those of the keymap profile
+    pub keys: BTreeMap<String, Vec<String>>,
+    /// Game controller inputs by Chip8 key replacing those of the keymap profile
+    pub buttons: BTreeMap<String, Vec<String>>,
+    /// Keys not known
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+impl Config {
+    /// Read a configuration file. A missing file gives the default configuration
+    pub fn load(path: &Path) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => text.parse(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    /// Settings of a ROM if there are any
+    pub fn rom(&self, program: &[u8]) -> Option<&RomConfig> {
+        self.roms.get(&rom_id(program))
+    }
+
+    /// The global settings with those of the ROM on top
+    pub fn settings(&self, program: &[u8]) -> Settings {
+        match self.rom(program) {
+            Some(rom) => self.settings.layered(&rom.settings),
+            None => self.settings.clone(),
+        }
+    }
+
+    /// Name of the keymap profile for a program: the one given, the one of the ROM, the global one or qwerty in that order
+    pub fn keymap_name<'a>(&'a self, profile: Option<&'a str>, program: &[u8]) -> &'a str {
+        profile
+            .or(self.rom(program).and_then(|rom| rom.keymap.as_deref()))
+            .or(self.keymap.as_deref())
+            .unwrap_or(DEFAULT_KEYMAP)
+    }
+
+    /// The keymap for a program. The profile is chosen by [Config::keymap_name], with the keys and buttons of the ROM applied on top
+    pub fn keymap(&self, profile: Option<&str>, program: &[u8]) -> Result<Keymap, String> {
+        let rom = self.rom(program);
+        let name = self.keymap_name(profile, program);
+
+        let mut keymap = match (Keymap::profile(name), self.keymaps.get(name)) {
+            (_, Some(custom)) => {
+                let mut keymap = match custom.base.as_deref() {
+                    Some(base) => Keymap::profile(base)
+                        .ok_or_else(|| format!("unknown base keymap {base} of keymap {name}"))?,
+                    None => Keymap::default(),
+                };
+                keymap.apply(&custom.keys, &custom.buttons)?;
+                keymap
+            }
+            (Some(keymap), None) => keymap,
+            (None, None) => return Err(format!("unknown keymap {name}")),
+        };
+
+        if let Some(rom) = rom {
+            keymap.apply(&rom.keys, &rom.buttons)?;
+        }
+        keymap.deadzone = self.deadzone.unwrap_or(keymap.deadzone);
+
+        Ok(keymap)
+    }
+}
+
+impl FromStr for Config {
+    type Err = String;
+
+    /// Parse the TOML of a configuration file. Unknown keys are an error, so typos don't go unnoticed
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: Config = toml::from_str(s).map_err(|error| error.to_string())?;
+
+        if let Some(key) = config.unknown.keys().next() {
+            return Err(format!("unknown key {key}"));
+        }
+        for (id, rom) in &config.roms {
+            if let Some(key) = rom.unknown.keys().next() {
+                return Err(format!("unknown key {key} of ROM {id}"));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Options as named on the command line. Those not set are left to the command line or its defaults
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Settings {
+    #[serde(deserialize_with = "parse")]
+    pub platform: Option<Platform>,
+    pub load_address: Option<usize>,
+    pub fps: Option<u16>,
+    pub mul: Option<u16>,
+    pub ips: Option<u32>,
+    #[serde(deserialize_with = "parse")]
+    pub speed: Option<Speed>,
+    #[serde(deserialize_with = "parse")]
+    pub timing: Option<Timing>,
+    pub scale: Option<u8>,
+    pub color: Option<u32>,
+    pub background: Option<u32>,
+    pub plane2_color: Option<u32>,
+    pub overlap_color: Option<u32>,
+    pub pitch: Option<u16>,
+    pub rewind_frames: Option<usize>,
+    pub quirks: QuirkSettings,
+}
+
+/// Quirks as named on the command line without the quirk- prefix
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct QuirkSettings {
+    pub vf_reset: Option<bool>,
+    pub memory: Option<bool>,
+    pub display_wait: Option<bool>,
+    pub clipping: Option<bool>,
+    pub shifting: Option<bool>,
+    pub jumping: Option<bool>,
+    pub key_press: Option<bool>,
+}
+
+impl Settings {
+    /// These settings overridden by those set in another
+    pub fn layered(&self, over: &Settings) -> Settings {
+        let (q, o) = (&self.quirks, &over.quirks);
+        // Either sets the speed, so one set on top replaces both
+        let (mul, ips) = if over.mul.is_some() || over.ips.is_some() {
+            (over.mul, over.ips)
+        } else {
+            (self.mul, self.ips)
+        };
+
+        Settings {
+            platform: over.platform.or(self.platform),
+            load_address: over.load_address.or(self.load_address),
+            fps: over.fps.or(self.fps),
+            mul,
+            ips,
+            speed: over.speed.or(self.speed),
+            timing: over.timing.or(self.timing),
+            scale: over.scale.or(self.scale),
+            color: over.color.or(self.color),
+            background: over.background.or(self.background),
+            plane2_color: over.plane2_color.or(self.plane2_color),
+            overlap_color: over.overlap_color.or(self.overlap_color),
+            pitch: over.pitch.or(self.pitch),
+            rewind_frames: over.rewind_frames.or(self.rewind_frames),
+            quirks: QuirkSettings {
+                vf_reset: o.vf_reset.or(q.vf_reset),
+                memory: o.memory.or(q.memory),
+                display_wait: o.display_wait.or(q.display_wait),
+                clipping: o.clipping.or(q.clipping),
+                shifting: o.shifting.or(q.shifting),
+                jumping: o.jumping.or(q.jumping),
+                key_press: o.key_press.or(q.key_press),
+            },
+        }
+    }
+}
+
+/// Deserialize a value by its name, e.g. a platform
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let name = String::deserialize(deserializer)?;
+    name.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Path of the configuration file in $XDG_CONFIG_HOME, or ~/.config if not set
+pub fn config_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(dir.join(CONFIG_FILE))
+}
+
+/// SHA-1 of a program in hex, identifying the ROM in the configuration file
+pub fn rom_id(program: &[u8]) -> String {
+    Sha1::digest(program)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: [u8; 2] = [0x12, 0x00];
+
+    fn config(rom: &str) -> Config {
+        format!(
+            "fps = 30\nspeed = \"2x\"\nkeymap = \"azerty\"\n\n[quirks]\nshifting = true\n\n[roms.{}]\n{}",
+            rom_id(&PROGRAM),
+            rom
+        )
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn rom_settings_override_global_ones() {
+        let config = config("speed = \"0.5x\"\ntiming = \"vip\"\nquirks = { shifting = false }");
+        let settings = config.settings(&PROGRAM);
+
+        // Global only
+        assert_eq!(settings.fps, Some(30));
+        // Global and ROM
+        assert_eq!(settings.speed, Some(Speed::Half));
+        assert_eq!(settings.quirks.shifting, Some(false));
+        // ROM only
+        assert_eq!(settings.timing, Some(Timing::Vip));
+        // Neither
+        assert_eq!(settings.platform, None);
+
+        // Other ROMs get the global settings
+        let other = config.settings(&[0x00, 0xE0]);
+        assert_eq!(other.speed, Some(Speed::Double));
+        assert_eq!(other.quirks.shifting, Some(true));
+        assert_eq!(other.timing, None);
+    }
+
+    #[test]
+    fn rom_speed_replaces_both_mul_and_ips() {
+        let config: Config = format!("mul = 20\n[roms.{}]\nips = 500", rom_id(&PROGRAM))
+            .parse()
+            .unwrap();
+        let settings = config.settings(&PROGRAM);
+
+        assert_eq!((settings.mul, settings.ips), (None, Some(500)));
+    }
+
+    #[test]
+    fn keymap_profile_precedence() {
+        let config = config("keymap = \"numpad\"");
+        assert_eq!(config.keymap_name(Some("qwerty"), &PROGRAM), "qwerty");
+        assert_eq!(config.keymap_name(None, &PROGRAM), "numpad");
+        assert_eq!(config.keymap_name(None, &[0x00, 0xE0]), "azerty");
+        assert_eq!(Config::default().keymap_name(None, &PROGRAM), "qwerty");
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let error = |toml: &str| toml.parse::<Config>().unwrap_err();
+
+        assert_eq!(error("spead = \"2x\""), "unknown key spead");
+        assert!(error("[quirks]\nshift = true").contains("unknown field `shift`"));
+        assert!(error("[keymaps.mine]\nbse = \"qwerty\"").contains("unknown field `bse`"));
+        assert_eq!(error("[roms.abc]\nfsp = 30"), "unknown key fsp of ROM abc");
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!("platform = \"c64\"".parse::<Config>().is_err());
+        assert!("fps = \"fast\"".parse::<Config>().is_err());
+    }
+}
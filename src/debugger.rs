@@ -0,0 +1,288 @@
+//! Debugging of a running Chip8 with pause, single step, breakpoints and watchpoints, independent of the frontend
+use std::{collections::BTreeSet, fmt, ops::Range, str::FromStr};
+
+use crate::chip8::{Chip8, Instruction, Instruction::*, NUMBER_OF_PLANES};
+
+/// What a watchpoint watches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    /// A byte of memory at an address
+    Memory(usize),
+    /// A general purpose register V0-VF
+    Register(usize),
+}
+
+/// Stop before an instruction reads and/or writes a memory address or register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub watch: Watch,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    /// Parse `<address|register>[:r|w|rw]`, e.g. `0x300:w` or `V3`. Default is to watch both reads and writes
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, access) = s.split_once(':').unwrap_or((s, "rw"));
+        let watch = match target.strip_prefix(['V', 'v']) {
+            Some(digit) if digit.len() == 1 => Watch::Register(
+                usize::from_str_radix(digit, 16)
+                    .map_err(|_| format!("invalid register {target}"))?,
+            ),
+            _ => Watch::Memory(parse_address(target)?),
+        };
+        let (read, write) = match access {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" | "wr" => (true, true),
+            _ => return Result::Err(format!("invalid access {access}, expected r, w or rw")),
+        };
+
+        Ok(Watchpoint { watch, read, write })
+    }
+}
+
+/// Parse an address as hex with 0x prefix or decimal
+pub fn parse_address(s: &str) -> Result<usize, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid address {s}"))
+}
+
+/// Why execution stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// Paused by the user
+    Paused,
+    /// A single instruction was executed
+    Stepped,
+    /// A breakpoint at the address was reached
+    Breakpoint(usize),
+    /// The next instruction accesses a watched location. Write is true for a write, false for a read
+    Watchpoint { watch: Watch, write: bool },
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Paused => write!(f, "PAUSED"),
+            Stop::Stepped => write!(f, "STEP"),
+            Stop::Breakpoint(address) => write!(f, "BREAK {:04X}", address),
+            Stop::Watchpoint { watch, write } => {
+                let access = if *write { "WRITE" } else { "READ" };
+                match watch {
+                    Watch::Memory(address) => write!(f, "{} {:04X}", access, address),
+                    Watch::Register(x) => write!(f, "{} V{:X}", access, x),
+                }
+            }
+        }
+    }
+}
+
+/// The debugger state of a machine
+#[derive(Debug, Clone, Default)]
+pub struct Debugger {
+    /// Debugger mode is on. Frontends show the state of the Chip8
+    pub enabled: bool,
+    /// Execution is paused
+    pub paused: bool,
+    /// Number of single steps to execute while paused
+    pub steps: usize,
+    /// Why execution last stopped
+    pub stop: Option<Stop>,
+    /// Addresses to stop at
+    pub breakpoints: BTreeSet<usize>,
+    /// Locations to watch
+    pub watchpoints: Vec<Watchpoint>,
+    /// Don't check the next instruction, since execution was resumed on it
+    skip_check: bool,
+}
+
+impl Debugger {
+    /// Pause execution
+    pub fn pause(&mut self, stop: Stop) {
+        self.paused = true;
+        self.stop = Some(stop);
+    }
+
+    /// Resume execution. Breakpoints and watchpoints on the current instruction are ignored
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.stop = None;
+        self.skip_check = true;
+    }
+
+    /// Pause if running, resume if paused
+    pub fn toggle_pause(&mut self) {
+        if self.paused {
+            self.resume();
+        } else {
+            self.pause(Stop::Paused);
+        }
+    }
+
+    /// Execute one instruction and pause again
+    pub fn step(&mut self) {
+        if !self.paused {
+            self.pause(Stop::Paused);
+        }
+        self.steps += 1;
+    }
+
+    /// Add the breakpoint if not set, otherwise remove it
+    pub fn toggle_breakpoint(&mut self, address: usize) {
+        if !self.breakpoints.remove(&address) {
+            self.breakpoints.insert(address);
+        }
+    }
+
+    /// Check if execution should stop before the next instruction of the Chip8
+    pub fn check(&mut self, chip8: &Chip8) -> Option<Stop> {
+        if std::mem::take(&mut self.skip_check) {
+            return None;
+        }
+
+        if self.breakpoints.contains(&chip8.pc()) {
+            return Some(Stop::Breakpoint(chip8.pc()));
+        }
+
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+
+        let opcode = chip8.fetch().ok()?;
+        let accesses = Accesses::of(chip8, Chip8::decode(opcode, chip8.platform));
+
+        self.watchpoints.iter().find_map(|w| {
+            let (read, write) = match w.watch {
+                Watch::Memory(address) => (
+                    accesses.memory_read.contains(&address),
+                    accesses.memory_write.contains(&address),
+                ),
+                Watch::Register(x) => (
+                    accesses.registers_read & (1 << x) != 0,
+                    accesses.registers_written & (1 << x) != 0,
+                ),
+            };
+
+            if w.write && write {
+                Some(Stop::Watchpoint {
+                    watch: w.watch,
+                    write: true,
+                })
+            } else if w.read && read {
+                Some(Stop::Watchpoint {
+                    watch: w.watch,
+                    write: false,
+                })
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Memory and registers an instruction accesses when executed on a Chip8 in its current state. Fetching the instruction is not included
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Accesses {
+    /// Memory addresses read
+    pub memory_read: Range<usize>,
+    /// Memory addresses written
+    pub memory_write: Range<usize>,
+    /// Registers read as bit mask (bit x for VX)
+    pub registers_read: u16,
+    /// Registers written as bit mask (bit x for VX)
+    pub registers_written: u16,
+}
+
+impl Accesses {
+    /// The accesses of the instruction on the Chip8
+    pub fn of(chip8: &Chip8, instr: Instruction) -> Self {
+        let i = chip8.i();
+        let reg = |x: usize| 1u16 << x;
+        // Registers x to y in either order
+        let regs = |x: usize, y: usize| (x.min(y)..=x.max(y)).fold(0, |mask, r| mask | reg(r));
+        let mut a = Accesses::default();
+
+        match instr {
+            Skeb(x, _)
+            | Skneb(x, _)
+            | Skp(x)
+            | Sknp(x)
+            | Ldtt(x)
+            | Ldst(x)
+            | Addi(x)
+            | Font(x)
+            | Hfont(x)
+            | Pitch(x) => a.registers_read = reg(x),
+            Ske(x, y) | Skne(x, y) => a.registers_read = reg(x) | reg(y),
+            Ldb(x, _) | Rnd(x, _) | Ldft(x) | Ldkp(x) => a.registers_written = reg(x),
+            Addb(x, _) => {
+                a.registers_read = reg(x);
+                a.registers_written = reg(x);
+            }
+            Ld(x, y) => {
+                a.registers_read = reg(y);
+                a.registers_written = reg(x);
+            }
+            Or(x, y) | And(x, y) | Xor(x, y) => {
+                a.registers_read = reg(x) | reg(y);
+                a.registers_written = reg(x) | if chip8.quirks.vf_reset { reg(0xF) } else { 0 };
+            }
+            Add(x, y) | Sub(x, y) | Subr(x, y) => {
+                a.registers_read = reg(x) | reg(y);
+                a.registers_written = reg(x) | reg(0xF);
+            }
+            Shr(x, y) | Shl(x, y) => {
+                a.registers_read = reg(if chip8.quirks.shifting { x } else { y });
+                a.registers_written = reg(x) | reg(0xF);
+            }
+            Jmpz(nnn) => {
+                a.registers_read = reg(if chip8.quirks.jumping { nnn >> 8 } else { 0 });
+            }
+            Draw(x, y, n) => {
+                let size = if n == 0 && chip8.platform.schip() {
+                    32
+                } else {
+                    n as usize
+                };
+                let planes = (0..NUMBER_OF_PLANES)
+                    .filter(|p| chip8.plane() & (1 << p) != 0)
+                    .count();
+                a.registers_read = reg(x) | reg(y);
+                a.registers_written = reg(0xF);
+                a.memory_read = i..(i + size * planes);
+            }
+            Bcd(x) => {
+                a.registers_read = reg(x);
+                a.memory_write = i..(i + 3);
+            }
+            Sreg(x) => {
+                a.registers_read = regs(0, x);
+                a.memory_write = i..(i + x + 1);
+            }
+            Lreg(x) => {
+                a.registers_written = regs(0, x);
+                a.memory_read = i..(i + x + 1);
+            }
+            Srpl(x) => a.registers_read = regs(0, x),
+            Lrpl(x) => a.registers_written = regs(0, x),
+            Sregr(x, y) => {
+                a.registers_read = regs(x, y);
+                a.memory_write = i..(i + x.abs_diff(y) + 1);
+            }
+            Lregr(x, y) => {
+                a.registers_written = regs(x, y);
+                a.memory_read = i..(i + x.abs_diff(y) + 1);
+            }
+            Audio => a.memory_read = i..(i + 16),
+            _ => {}
+        }
+
+        a
+    }
+}
@@ -0,0 +1,387 @@
+//! Disassembly of Chip8 programs into a listing in the project syntax or in Octo syntax
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use crate::chip8::{Chip8, Instruction, Instruction::*, Platform};
+
+/// Syntax of the disassembly listing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Syntax {
+    /// The mnemonics of [Instruction] (SKEB, LDB, DRAW...)
+    #[default]
+    Chip8rs,
+    /// The syntax of Octo
+    Octo,
+}
+
+impl Syntax {
+    /// Names of all syntaxes as used on the command line
+    pub const NAMES: [&'static str; 2] = ["chip8rs", "octo"];
+}
+
+impl fmt::Display for Syntax {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Syntax::NAMES[*self as usize])
+    }
+}
+
+impl FromStr for Syntax {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chip8rs" => Ok(Syntax::Chip8rs),
+            "octo" => Ok(Syntax::Octo),
+            _ => Result::Err(format!("unknown syntax {s}")),
+        }
+    }
+}
+
+/// A line of a listing. Either an instruction or data bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    /// Address of the first byte
+    pub address: usize,
+    /// The raw bytes
+    pub bytes: Vec<u8>,
+    /// Mnemonic of the instruction or data directive
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let raw: Vec<String> = self
+            .bytes
+            .chunks(2)
+            .map(|word| word.iter().map(|b| format!("{:02X}", b)).collect())
+            .collect();
+        write!(
+            f,
+            "{:04X}  {:<19} {}",
+            self.address,
+            raw.join(" "),
+            self.text
+        )
+    }
+}
+
+/// Maximum number of data bytes per line
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// Disassemble a program loaded at load_address. Unless linear, only code reachable from the load address is decoded as instructions and the rest is shown as data
+pub fn disassemble(
+    program: &[u8],
+    load_address: usize,
+    platform: Platform,
+    syntax: Syntax,
+    linear: bool,
+) -> Vec<Line> {
+    let starts = if linear {
+        (0..program.len()).step_by(2).collect()
+    } else {
+        analyze(program, load_address, platform)
+    };
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+
+    while offset < program.len() {
+        if starts.contains(&offset) && offset + 1 < program.len() {
+            let opcode = word(program, offset).unwrap();
+            let instr = Chip8::decode(opcode, platform);
+            let size = instruction_size(instr);
+            let next = (size == 4).then(|| word(program, offset + 2)).flatten();
+            let end = (offset + size).min(program.len());
+
+            lines.push(Line {
+                address: load_address + offset,
+                bytes: program[offset..end].to_vec(),
+                text: format_instruction(instr, opcode, next, syntax),
+            });
+            offset = end;
+        } else {
+            let end = (offset + 1..program.len())
+                .find(|o| starts.contains(o) || o - offset == DATA_BYTES_PER_LINE)
+                .unwrap_or(program.len());
+            let bytes = program[offset..end].to_vec();
+
+            lines.push(Line {
+                address: load_address + offset,
+                text: format_data(&bytes, syntax),
+                bytes,
+            });
+            offset = end;
+        }
+    }
+
+    lines
+}
+
+/// Find the offsets of all instructions reachable from the start of the program by following jumps, calls and skips (recursive descent)
+pub fn analyze(program: &[u8], load_address: usize, platform: Platform) -> BTreeSet<usize> {
+    let mut starts = BTreeSet::new();
+    let mut covered = vec![false; program.len()];
+    let mut work = vec![0];
+
+    while let Some(offset) = work.pop() {
+        let Some(opcode) = word(program, offset) else {
+            continue;
+        };
+
+        if starts.contains(&offset) || covered[offset] || covered[offset + 1] {
+            continue;
+        }
+
+        let instr = Chip8::decode(opcode, platform);
+        if instr == Err {
+            continue;
+        }
+
+        let size = instruction_size(instr);
+        starts.insert(offset);
+        for byte in covered.iter_mut().skip(offset).take(size) {
+            *byte = true;
+        }
+
+        // Offset of an address if within the program
+        let target = |address: usize| address.checked_sub(load_address);
+
+        match instr {
+            Jmp(nnn) => work.extend(target(nnn)),
+            Call(nnn) => {
+                work.extend(target(nnn));
+                work.push(offset + size);
+            }
+            // Targets of jumps with offset are unknown
+            Ret | Exit | Jmpz(_) => {}
+            Skeb(..) | Skneb(..) | Ske(..) | Skne(..) | Skp(_) | Sknp(_) => {
+                let next = offset + size;
+                let skipped = match word(program, next) {
+                    Some(0xF000) if platform == Platform::XoChip => 4,
+                    _ => 2,
+                };
+                work.push(next);
+                work.push(next + skipped);
+            }
+            _ => work.push(offset + size),
+        }
+    }
+
+    starts
+}
+
+/// The word at offset in the program, if any
+fn word(program: &[u8], offset: usize) -> Option<u16> {
+    match program.get(offset..offset + 2) {
+        Some(&[high, low]) => Some((high as u16) << 8 | low as u16),
+        _ => None,
+    }
+}
+
+/// Format the instruction at an address in memory for a platform, if the address is within memory
+pub fn format_at(
+    memory: &[u8],
+    address: usize,
+    platform: Platform,
+    syntax: Syntax,
+) -> Option<String> {
+    let opcode = word(memory, address)?;
+    let instr = Chip8::decode(opcode, platform);
+
+    Some(format_instruction(
+        instr,
+        opcode,
+        word(memory, address + 2),
+        syntax,
+    ))
+}
+
+/// Size of an instruction in bytes. The long load F000 nnnn is two words long (XO-CHIP)
+pub fn instruction_size(instr: Instruction) -> usize {
+    if instr == Ldil {
+        4
+    } else {
+        2
+    }
+}
+
+/// Format data bytes as a directive
+pub fn format_data(bytes: &[u8], syntax: Syntax) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+
+    match syntax {
+        Syntax::Chip8rs => format!("DB {}", bytes.join(", ")),
+        Syntax::Octo => bytes.join(" "),
+    }
+}
+
+/// Format an instruction with its opcode and the next word (used by the long load) in a syntax
+pub fn format_instruction(
+    instr: Instruction,
+    opcode: u16,
+    next: Option<u16>,
+    syntax: Syntax,
+) -> String {
+    let nnn = opcode & 0x0FFF;
+    let long = next.unwrap_or(0);
+
+    match syntax {
+        Syntax::Chip8rs => match instr {
+            Sys => format!("SYS 0x{:03X}", nnn),
+            Cls => "CLS".to_string(),
+            Ret => "RET".to_string(),
+            Scd(n) => format!("SCD {}", n),
+            Scu(n) => format!("SCU {}", n),
+            Scr => "SCR".to_string(),
+            Scl => "SCL".to_string(),
+            Exit => "EXIT".to_string(),
+            Low => "LOW".to_string(),
+            High => "HIGH".to_string(),
+            Jmp(nnn) => format!("JMP 0x{:03X}", nnn),
+            Call(nnn) => format!("CALL 0x{:03X}", nnn),
+            Skeb(x, kk) => format!("SKEB V{:X}, 0x{:02X}", x, kk),
+            Skneb(x, kk) => format!("SKNEB V{:X}, 0x{:02X}", x, kk),
+            Ske(x, y) => format!("SKE V{:X}, V{:X}", x, y),
+            Skne(x, y) => format!("SKNE V{:X}, V{:X}", x, y),
+            Ldb(x, kk) => format!("LDB V{:X}, 0x{:02X}", x, kk),
+            Addb(x, kk) => format!("ADDB V{:X}, 0x{:02X}", x, kk),
+            Ld(x, y) => format!("LD V{:X}, V{:X}", x, y),
+            Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+            And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+            Add(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+            Subr(x, y) => format!("SUBR V{:X}, V{:X}", x, y),
+            Shr(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+            Shl(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+            Ldi(nnn) => format!("LDI 0x{:03X}", nnn),
+            Jmpz(nnn) => format!("JMPZ 0x{:03X}", nnn),
+            Rnd(x, kk) => format!("RND V{:X}, 0x{:02X}", x, kk),
+            Draw(x, y, n) => format!("DRAW V{:X}, V{:X}, {}", x, y, n),
+            Skp(x) => format!("SKP V{:X}", x),
+            Sknp(x) => format!("SKNP V{:X}", x),
+            Ldft(x) => format!("LDFT V{:X}", x),
+            Ldkp(x) => format!("LDKP V{:X}", x),
+            Ldtt(x) => format!("LDTT V{:X}", x),
+            Ldst(x) => format!("LDST V{:X}", x),
+            Addi(x) => format!("ADDI V{:X}", x),
+            Font(x) => format!("FONT V{:X}", x),
+            Hfont(x) => format!("HFONT V{:X}", x),
+            Bcd(x) => format!("BCD V{:X}", x),
+            Sreg(x) => format!("SREG V{:X}", x),
+            Lreg(x) => format!("LREG V{:X}", x),
+            Srpl(x) => format!("SRPL V{:X}", x),
+            Lrpl(x) => format!("LRPL V{:X}", x),
+            Ldil => format!("LDIL 0x{:04X}", long),
+            Sregr(x, y) => format!("SREGR V{:X}, V{:X}", x, y),
+            Lregr(x, y) => format!("LREGR V{:X}, V{:X}", x, y),
+            Plane(n) => format!("PLANE {}", n),
+            Audio => "AUDIO".to_string(),
+            Pitch(x) => format!("PITCH V{:X}", x),
+            Err => format_data(&opcode.to_be_bytes(), syntax),
+        },
+        Syntax::Octo => match instr {
+            Sys => format_data(&opcode.to_be_bytes(), syntax),
+            Cls => "clear".to_string(),
+            Ret => "return".to_string(),
+            Scd(n) => format!("scroll-down {}", n),
+            Scu(n) => format!("scroll-up {}", n),
+            Scr => "scroll-right".to_string(),
+            Scl => "scroll-left".to_string(),
+            Exit => "exit".to_string(),
+            Low => "lores".to_string(),
+            High => "hires".to_string(),
+            Jmp(nnn) => format!("jump 0x{:03X}", nnn),
+            Call(nnn) => format!(":call 0x{:03X}", nnn),
+            // Octo expresses skips as conditions for executing the next instruction
+            Skeb(x, kk) => format!("if v{:x} != 0x{:02X} then", x, kk),
+            Skneb(x, kk) => format!("if v{:x} == 0x{:02X} then", x, kk),
+            Ske(x, y) => format!("if v{:x} != v{:x} then", x, y),
+            Skne(x, y) => format!("if v{:x} == v{:x} then", x, y),
+            Ldb(x, kk) => format!("v{:x} := 0x{:02X}", x, kk),
+            Addb(x, kk) => format!("v{:x} += 0x{:02X}", x, kk),
+            Ld(x, y) => format!("v{:x} := v{:x}", x, y),
+            Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+            And(x, y) => format!("v{:x} &= v{:x}", x, y),
+            Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+            Add(x, y) => format!("v{:x} += v{:x}", x, y),
+            Sub(x, y) => format!("v{:x} -= v{:x}", x, y),
+            Subr(x, y) => format!("v{:x} =- v{:x}", x, y),
+            Shr(x, y) => format!("v{:x} >>= v{:x}", x, y),
+            Shl(x, y) => format!("v{:x} <<= v{:x}", x, y),
+            Ldi(nnn) => format!("i := 0x{:03X}", nnn),
+            Jmpz(nnn) => format!("jump0 0x{:03X}", nnn),
+            Rnd(x, kk) => format!("v{:x} := random 0x{:02X}", x, kk),
+            Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+            Skp(x) => format!("if v{:x} -key then", x),
+            Sknp(x) => format!("if v{:x} key then", x),
+            Ldft(x) => format!("v{:x} := delay", x),
+            Ldkp(x) => format!("v{:x} := key", x),
+            Ldtt(x) => format!("delay := v{:x}", x),
+            Ldst(x) => format!("buzzer := v{:x}", x),
+            Addi(x) => format!("i += v{:x}", x),
+            Font(x) => format!("i := hex v{:x}", x),
+            Hfont(x) => format!("i := bighex v{:x}", x),
+            Bcd(x) => format!("bcd v{:x}", x),
+            Sreg(x) => format!("save v{:x}", x),
+            Lreg(x) => format!("load v{:x}", x),
+            Srpl(x) => format!("saveflags v{:x}", x),
+            Lrpl(x) => format!("loadflags v{:x}", x),
+            Ldil => format!("i := long 0x{:04X}", long),
+            Sregr(x, y) => format!("save v{:x} - v{:x}", x, y),
+            Lregr(x, y) => format!("load v{:x} - v{:x}", x, y),
+            Plane(n) => format!("plane {}", n),
+            Audio => "audio".to_string(),
+            Pitch(x) => format!("pitch := v{:x}", x),
+            Err => format_data(&opcode.to_be_bytes(), syntax),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn analyze_separates_code_from_data() {
+        let program = [
+            0x22, 0x08, // 0x200 CALL 0x208
+            0x30, 0x01, // 0x202 SKEB V0, 1
+            0x12, 0x00, // 0x204 JMP 0x200
+            0x12, 0x0C, // 0x206 JMP 0x20C
+            0xA2, 0x0E, // 0x208 LDI 0x20E
+            0x00, 0xEE, // 0x20A RET
+            0x00, 0xFD, // 0x20C EXIT
+            0xF0, 0x90, 0xF0, // 0x20E sprite data, looks like instructions
+        ];
+
+        let starts = analyze(&program, 0x200, Platform::SchipModern);
+        assert_eq!(
+            starts.into_iter().collect::<Vec<_>>(),
+            [0x0, 0x2, 0x4, 0x6, 0x8, 0xA, 0xC]
+        );
+
+        let lines = disassemble(
+            &program,
+            0x200,
+            Platform::SchipModern,
+            Syntax::Chip8rs,
+            false,
+        );
+        let last = lines.last().unwrap();
+        assert_eq!(last.address, 0x20E);
+        assert_eq!(last.text, "DB 0xF0, 0x90, 0xF0");
+    }
+
+    #[test]
+    fn analyze_skips_over_long_load() {
+        let program = [
+            0x30, 0x01, // 0x200 SKEB V0, 1
+            0xF0, 0x00, 0x12, 0x34, // 0x202 LDIL 0x1234
+            0x00, 0xFD, // 0x206 EXIT
+            0x12, 0x34, // 0x208 data
+        ];
+
+        let starts = analyze(&program, 0x200, Platform::XoChip);
+        assert_eq!(starts.into_iter().collect::<Vec<_>>(), [0x0, 0x2, 0x6]);
+    }
+}
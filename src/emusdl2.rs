@@ -1,71 +1,152 @@
-use crate::chip8::{Chip8, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::chip8::{Chip8, AUDIO_PATTERN_SIZE, KEYBOARD_SIZE};
+use crate::debugger::{Debugger, Stop};
+use crate::disasm::{format_at, Syntax};
+use crate::gdb::GdbStub;
+use crate::keymap::Keymap;
+use crate::machine::{AudioSink, Control, InputSource, Machine, VideoSink};
+use crate::movie::Movie;
+use crate::profile::Profiler;
+use crate::rewind::Rewind;
+use crate::timing::{Speed, Timing};
+use crate::trace::Tracer;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
 use sdl2::{
-    audio::{AudioCallback, AudioSpecDesired, AudioStatus},
+    audio::{AudioCallback, AudioDevice, AudioSpecDesired, AudioStatus},
+    controller::{Axis, Button, GameController},
     pixels::PixelFormatEnum,
-    render::BlendMode,
+    render::{BlendMode, Canvas, Texture, TextureCreator},
+    video::{Window, WindowContext},
+    EventPump, GameControllerSubsystem,
 };
 use sdl2::{
     event::Event,
     keyboard::{Keycode, Scancode},
+    messagebox::{show_simple_message_box, MessageBoxFlag},
     pixels::Color,
-    rect::Point,
-};
-use std::{
-    thread::sleep,
-    time::{Duration, Instant},
+    rect::{Point, Rect},
 };
 
 /// An emulator of the Chip8 model using SDL2 for keyboard input, video and sound
 pub struct EmuSdl2 {
-    /// The Chip8 instance to run
-    chip8: Chip8,
-    /// Frames per second. 60 is the default
-    fps: u16,
-    /// Target instructions per second as multiplier of fps. 20 is the recommended default
-    mul: u16,
-    /// Scale display by this number. Original display is 64x32 pixels. 10 or more is the recommended default
+    /// The Chip8 instance to run with its frame scheduling
+    machine: Machine,
+    /// Scale display by this number. Original display is 64x32 pixels and SUPER-CHIP is 128x64 pixels. 10 or more is the recommended default
     scale: u8,
     /// Foreground color
     color: u32,
     /// Background color
     background: u32,
+    /// Color of pixels set only in plane 2 (XO-CHIP)
+    plane2_color: u32,
+    /// Color of pixels set in both planes (XO-CHIP)
+    overlap_color: u32,
     /// Pitch of buzzer
     pitch: u16,
+    /// Host keys of the Chip8 keys
+    keymap: Keymap,
+    /// Port on localhost to serve the GDB remote serial protocol on
+    gdb: Option<u16>,
 }
 
+/// Width of the debugger window in characters
+const PANEL_COLUMNS: u32 = 30;
+/// Height of the debugger window in lines
+const PANEL_LINES: u32 = 16;
+/// Scale of the debugger font
+const PANEL_SCALE: u32 = 3;
+
 #[derive(Debug)]
 pub struct Options {
     pub fps: u16,
-    pub mul: u16,
+    pub ips: u32,
+    pub speed: Speed,
+    pub timing: Timing,
     pub scale: u8,
     pub color: u32,
     pub background: u32,
+    pub plane2_color: u32,
+    pub overlap_color: u32,
     pub pitch: u16,
+    /// Host keys of the Chip8 keys
+    pub keymap: Keymap,
+    /// Breakpoints, watchpoints and whether to start in debugger mode
+    pub debugger: Debugger,
+    /// Port on localhost to serve the GDB remote serial protocol on
+    pub gdb: Option<u16>,
+    /// Trace of executed instructions
+    pub tracer: Option<Tracer>,
+    /// Profile of executed instructions
+    pub profiler: Option<Profiler>,
+    /// Path of the program. Save states are stored next to it
+    pub program: Option<PathBuf>,
+    /// Number of frames that can be rewound. 0 disables rewinding
+    pub rewind_frames: usize,
+    /// Recording or playback of the keyboard
+    pub movie: Option<Movie>,
 }
 
 impl EmuSdl2 {
     /// Create a new instance passing in binary program code and options
     pub fn new(chip8: Chip8, options: Options) -> Self {
+        let mut machine = Machine::new(chip8, options.fps, options.ips);
+        machine.speed = options.speed;
+        machine.timing = options.timing;
+        machine.debugger = options.debugger;
+        machine.tracer = options.tracer;
+        machine.profiler = options.profiler;
+        machine.program = options.program;
+        machine.movie = options.movie;
+        machine.rewind = (options.rewind_frames > 0).then(|| Rewind::new(options.rewind_frames));
+
         EmuSdl2 {
-            chip8,
-            fps: options.fps,
-            mul: options.mul,
+            machine,
             scale: options.scale,
             color: options.color,
             background: options.background,
+            plane2_color: options.plane2_color,
+            overlap_color: options.overlap_color,
             pitch: options.pitch,
+            keymap: options.keymap,
+            gdb: options.gdb,
         }
     }
 
-    /// Run the Chip8 at desired fps and instruction multiplier rate. Use SDL2 to obtain input and render graphics as well as sound
+    /// The machine with the Chip8, e.g. to inspect it after running
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Run the Chip8 at 60 frames per second and the instructions per second, presented at the desired fps. Use SDL2 to obtain input and render graphics as well as sound
     pub fn run(&mut self) {
+        // The Chip8 is paused until a GDB client attaches and continues
+        if let Some(port) = self.gdb {
+            match GdbStub::listen(port) {
+                Ok(stub) => {
+                    println!("Waiting for GDB on localhost:{}", port);
+                    self.machine.remote = Some(Box::new(stub));
+                    self.machine.debugger.pause(Stop::Paused);
+                }
+                Err(error) => {
+                    eprintln!(
+                        "Error: could not listen for GDB on port {}: {}",
+                        port, error
+                    );
+                    return;
+                }
+            }
+        }
+
         let sdl = sdl2::init().unwrap();
         let video = sdl.video().unwrap();
+        // The window fits the largest display of the platform
+        let (display_width, display_height) = self.machine.chip8.platform.display_size();
         let window = video
             .window(
                 "Chip8 Emulator",
-                DISPLAY_WIDTH as u32 * self.scale as u32,
-                DISPLAY_HEIGHT as u32 * self.scale as u32,
+                display_width as u32 * self.scale as u32,
+                display_height as u32 * self.scale as u32,
             )
             .position_centered()
             .build()
@@ -80,72 +161,52 @@ impl EmuSdl2 {
         // Support alpha blending
         canvas.set_blend_mode(BlendMode::Blend);
 
-        let background_color = Color::RGBA(
-            ((self.background & 0xff0000) >> 16) as u8,
-            ((self.background & 0x00ff00) >> 8) as u8,
-            (self.background & 0x0000ff) as u8,
-            ((self.background & 0xff000000) >> 24) as u8,
-        );
-
-        let foreground_color = Color::RGBA(
-            ((self.color & 0xff0000) >> 16) as u8,
-            ((self.color & 0x00ff00) >> 8) as u8,
-            (self.color & 0x0000ff) as u8,
-            ((self.color & 0xff000000) >> 24) as u8,
-        );
+        let background_color = argb(self.background);
+
+        // Colors of pixels indexed by the bitplanes set (0 is background)
+        let plane_colors = [
+            background_color,
+            argb(self.color),
+            argb(self.plane2_color),
+            argb(self.overlap_color),
+        ];
+
+        // The debugger panel is shown in a window of its own
+        let panel = self.machine.debugger.enabled.then(|| {
+            video
+                .window(
+                    "Chip8 Debugger",
+                    (PANEL_COLUMNS * 4 + 2) * PANEL_SCALE,
+                    (PANEL_LINES * 7 + 2) * PANEL_SCALE,
+                )
+                .build()
+                .unwrap()
+                .into_canvas()
+                .build()
+                .unwrap()
+        });
 
-        // Create a grid as a texture
         let texture_creator = canvas.texture_creator();
-        let mut grid = texture_creator
-            .create_texture_target(
-                PixelFormatEnum::ARGB8888,
-                (DISPLAY_WIDTH * self.scale as usize) as u32,
-                (DISPLAY_HEIGHT * self.scale as usize) as u32,
-            )
-            .unwrap();
-        grid.set_blend_mode(BlendMode::Blend);
-
-        canvas
-            .with_texture_canvas(&mut grid, |c| {
-                let mut grid_color = background_color;
-                grid_color.a = 0x1d;
-                c.set_draw_color(grid_color);
-                // Draw horizontal lines
-                for y in 0..(DISPLAY_HEIGHT * self.scale as usize) {
-                    if y % (self.scale as usize) == 0 {
-                        c.draw_line(
-                            (0, y as i32),
-                            ((self.scale as usize * DISPLAY_WIDTH) as i32, y as i32),
-                        )
-                        .unwrap();
-                    }
-                }
-                // Draw vertical lines
-                for x in 0..(DISPLAY_WIDTH * self.scale as usize) {
-                    if x % (self.scale as usize) == 0 {
-                        c.draw_line(
-                            (x as i32, 0),
-                            (x as i32, (self.scale as usize * DISPLAY_HEIGHT) as i32),
-                        )
-                        .unwrap();
-                    }
-                }
-            })
-            .unwrap();
-
-        // The logical size is set to the size of the Chip8 display. It makes it possible to draw single pixels at the correct position and get a scaled display automatically
-        canvas
-            .set_logical_size(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32)
-            .unwrap();
+        let mut video = SdlVideo::new(
+            canvas,
+            &texture_creator,
+            plane_colors,
+            (
+                display_width * self.scale as usize,
+                display_height * self.scale as usize,
+            ),
+            &self.machine.chip8,
+            panel,
+        );
 
         println!(
             "{:?}, default_pixel_format: {:?}, scale: {:?}, logical_size: {:?}, output_size: {:?}, render_target_supported: {:?}",
-            canvas.info(),
-            canvas.default_pixel_format(),
-            canvas.scale(),
-            canvas.logical_size(),
-            canvas.output_size().unwrap(),
-            canvas.render_target_supported()
+            video.canvas.info(),
+            video.canvas.default_pixel_format(),
+            video.canvas.scale(),
+            video.canvas.logical_size(),
+            video.canvas.output_size().unwrap(),
+            video.canvas.render_target_supported()
         );
 
         // Audio
@@ -160,8 +221,10 @@ impl EmuSdl2 {
         let device = audio_subsystem
             .open_playback(None, &desired_spec, |spec| {
                 // initialize the audio callback
-                SquareWave {
-                    phase_inc: self.pitch as f32 / spec.freq as f32,
+                PatternPlayer {
+                    pattern: BUZZER_PATTERN,
+                    freq: spec.freq as f32,
+                    phase_inc: self.pitch as f32 * PATTERN_BITS / spec.freq as f32,
                     phase: 0.0,
                     volume: 0.25,
                 }
@@ -174,146 +237,590 @@ impl EmuSdl2 {
             audio_subsystem
         );
 
-        let mut events = sdl.event_pump().unwrap();
-
-        'main: loop {
-            let t = Instant::now();
-
-            // Handle input
-            for event in events.poll_iter() {
-                match event {
-                    // Quit
-                    Event::Quit { .. }
-                    | Event::KeyDown {
-                        keycode: Some(Keycode::Escape),
-                        ..
-                    } => break 'main,
-                    Event::KeyDown {
-                        scancode: Some(scancode),
-                        ..
-                    } => {
-                        if let Some(keycode) = self.keymap(scancode) {
-                            self.chip8.keyboard[keycode] = true;
-                            #[cfg(debug_assertions)]
-                            eprintln!("Key {:0x} down", keycode);
-                        }
-                    }
-                    Event::KeyUp {
-                        scancode: Some(scancode),
-                        ..
-                    } => {
-                        if let Some(keycode) = self.keymap(scancode) {
-                            self.chip8.keyboard[keycode] = false;
-                            #[cfg(debug_assertions)]
-                            eprintln!("Key {:0x} up", keycode);
-                        }
-                    }
-                    _ => {}
-                }
-            }
+        let mut audio = SdlAudio {
+            device,
+            pitch: self.pitch,
+        };
 
-            // Step the Chip8 mul times
-            for _ in 0..self.mul {
-                self.chip8.step();
+        // Game controllers. Those already connected are added by events like any plugged in later
+        // Without the game controller subsystem only the keyboard is used
+        let controller_subsystem = sdl
+            .game_controller()
+            .map_err(|error| eprintln!("Warning: game controllers are not supported: {}", error))
+            .ok();
+
+        let mut input = SdlInput {
+            events: sdl.event_pump().unwrap(),
+            slot: 0,
+            keys: scancodes(&self.keymap),
+            controller_subsystem,
+            controllers: HashMap::new(),
+            buttons: controller_inputs(&self.keymap),
+            deadzone: self.keymap.deadzone,
+        };
 
-                if self.chip8.quirks.display_wait && self.chip8.display_update {
-                    break;
-                }
-            }
+        let result = self.machine.run(&mut video, &mut audio, &mut input);
+        self.machine.flush_trace();
 
-            // Decrement delay timer if non-zero
-            if self.chip8.dt > 0 {
-                self.chip8.dt -= 1;
-            }
+        if let Err(error) = result {
+            eprintln!("Error: {}", error);
+            show_simple_message_box(
+                MessageBoxFlag::ERROR,
+                "Chip8 Emulator",
+                &format!("The program stopped with an error:\n{}", error),
+                video.canvas.window(),
+            )
+            .unwrap_or_default();
+        }
+    }
+}
 
-            // Decrement sound timer if non-zero and play sound
-            if self.chip8.st > 0 {
-                if device.status() != AudioStatus::Playing {
-                    device.resume();
-                }
-                self.chip8.st -= 1;
-            } else if device.status() != AudioStatus::Paused {
-                device.pause();
-            }
+/// Video output to an SDL2 canvas
+struct SdlVideo<'a> {
+    canvas: Canvas<Window>,
+    texture_creator: &'a TextureCreator<WindowContext>,
+    /// Grid drawn on top of the display
+    grid: Texture<'a>,
+    /// Colors of pixels indexed by the bitplanes set (0 is background)
+    plane_colors: [Color; 4],
+    /// Size of the window in pixels
+    window_size: (usize, usize),
+    /// Size of the Chip8 display currently shown
+    display_size: (usize, usize),
+    /// Window with the state of the Chip8 and the debugger if the debugger is enabled
+    panel: Option<Canvas<Window>>,
+}
+
+impl<'a> SdlVideo<'a> {
+    fn new(
+        mut canvas: Canvas<Window>,
+        texture_creator: &'a TextureCreator<WindowContext>,
+        plane_colors: [Color; 4],
+        window_size: (usize, usize),
+        chip8: &Chip8,
+        panel: Option<Canvas<Window>>,
+    ) -> Self {
+        let display_size = (chip8.display_width(), chip8.display_height());
+        let grid = create_grid(
+            &mut canvas,
+            texture_creator,
+            plane_colors[0],
+            window_size,
+            display_size,
+        );
 
-            // Draw display if Chip8 indicates display is updated
-            if self.chip8.display_update {
-                canvas.set_draw_color(background_color);
-                canvas.clear();
-                canvas.set_draw_color(foreground_color);
+        // The logical size is set to the size of the Chip8 display. It makes it possible to draw single pixels at the correct position and get a scaled display automatically
+        canvas
+            .set_logical_size(display_size.0 as u32, display_size.1 as u32)
+            .unwrap();
 
-                for y in 0..DISPLAY_HEIGHT {
-                    for x in 0..DISPLAY_WIDTH {
-                        if self.chip8.display[y][x] {
-                            canvas.draw_point(Point::new(x as i32, y as i32)).unwrap();
-                        }
-                    }
-                }
+        SdlVideo {
+            canvas,
+            texture_creator,
+            grid,
+            plane_colors,
+            window_size,
+            display_size,
+            panel,
+        }
+    }
+}
 
-                // Copy grid texture on top (could be configurable)
-                canvas.copy(&grid, None, None).unwrap();
+impl VideoSink for SdlVideo<'_> {
+    fn present(&mut self, chip8: &Chip8) {
+        // Resize logical size and grid if the Chip8 has changed resolution mode
+        let display_size = (chip8.display_width(), chip8.display_height());
+        if self.display_size != display_size {
+            self.display_size = display_size;
+            self.grid = create_grid(
+                &mut self.canvas,
+                self.texture_creator,
+                self.plane_colors[0],
+                self.window_size,
+                display_size,
+            );
+            self.canvas
+                .set_logical_size(display_size.0 as u32, display_size.1 as u32)
+                .unwrap();
+        }
 
-                canvas.present();
+        let (width, height) = display_size;
+        self.canvas.set_draw_color(self.plane_colors[0]);
+        self.canvas.clear();
+
+        for (planes, color) in self.plane_colors.iter().enumerate().skip(1) {
+            let points: Vec<Point> = (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .filter(|&(x, y)| chip8.display[y][x] as usize == planes)
+                .map(|(x, y)| Point::new(x as i32, y as i32))
+                .collect();
+            self.canvas.set_draw_color(*color);
+            self.canvas.draw_points(&points[..]).unwrap();
+        }
 
-                #[cfg(debug_assertions)]
-                eprintln!("Display updated");
+        // Copy grid texture on top (could be configurable)
+        self.canvas.copy(&self.grid, None, None).unwrap();
 
-                self.chip8.display_update = false; // Chip8 will set this to true whenever something changes on screen
-            }
+        self.canvas.present();
+
+        #[cfg(debug_assertions)]
+        eprintln!("Display updated");
+    }
+
+    fn present_debugger(&mut self, chip8: &Chip8, debugger: &Debugger) {
+        let Some(panel) = self.panel.as_mut() else {
+            return;
+        };
+
+        panel.set_draw_color(self.plane_colors[0]);
+        panel.clear();
+        panel.set_draw_color(self.plane_colors[1]);
 
-            let sleep_duration =
-                (1_000_000_000_i64 / self.fps as i64) - t.elapsed().as_nanos() as i64;
+        for (row, line) in debugger_lines(chip8, debugger).iter().enumerate() {
+            draw_text(panel, line, 1, 1 + row as i32 * 7);
+        }
 
-            #[cfg(debug_assertions)]
-            eprintln!("Sleeping {} ns", sleep_duration);
+        panel.present();
+    }
+}
+
+/// The lines of text shown in the debugger panel
+fn debugger_lines(chip8: &Chip8, debugger: &Debugger) -> Vec<String> {
+    let mut lines = vec![
+        format!(
+            "PC {:04X}  I {:04X}  SP {:X}",
+            chip8.pc(),
+            chip8.i(),
+            chip8.sp()
+        ),
+        format!(
+            "DT {:02X}  ST {:02X}  PLANE {}",
+            chip8.dt,
+            chip8.st,
+            chip8.plane()
+        ),
+        String::new(),
+    ];
+
+    for (row, registers) in chip8.registers().chunks(4).enumerate() {
+        let line: Vec<String> = registers
+            .iter()
+            .enumerate()
+            .map(|(column, v)| format!("V{:X} {:02X}", row * 4 + column, v))
+            .collect();
+        lines.push(line.join("  "));
+    }
+
+    lines.push(String::new());
+    lines.push("STACK".to_string());
+    for entries in chip8.stack()[..chip8.sp()].chunks(4) {
+        let line: Vec<String> = entries.iter().map(|a| format!("{:04X}", a)).collect();
+        lines.push(line.join(" "));
+    }
+
+    lines.push(String::new());
+    // The next instruction to execute
+    let memory = &chip8.memory()[..chip8.platform.memory_size()];
+    lines.push(format!(
+        "> {:04X} {}",
+        chip8.pc(),
+        format_at(memory, chip8.pc(), chip8.platform, Syntax::Chip8rs).unwrap_or_default()
+    ));
+    lines.push(match debugger.stop {
+        Some(stop) => stop.to_string(),
+        None => "RUNNING".to_string(),
+    });
+
+    lines
+}
+
+/// Draw text with a 3x5 pixel font at x, y on a canvas scaled by PANEL_SCALE
+fn draw_text(canvas: &mut Canvas<Window>, text: &str, x: i32, y: i32) {
+    let scale = PANEL_SCALE as i32;
+    let rects: Vec<Rect> = text
+        .to_uppercase()
+        .chars()
+        .enumerate()
+        .flat_map(|(column, c)| {
+            let glyph = glyph(c);
+            (0..5).flat_map(move |row| {
+                (0..3)
+                    .filter(move |bit| glyph[row] & (4 >> bit) != 0)
+                    .map(move |bit| {
+                        Rect::new(
+                            (x + column as i32 * 4 + bit) * scale,
+                            (y + row as i32) * scale,
+                            PANEL_SCALE,
+                            PANEL_SCALE,
+                        )
+                    })
+            })
+        })
+        .collect();
+    canvas.fill_rects(&rects).unwrap();
+}
+
+/// Rows of a 3x5 pixel character. Bit 2 is the leftmost pixel. Characters without a glyph are blank
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [7, 5, 5, 5, 7],
+        '1' => [2, 6, 2, 2, 7],
+        '2' => [7, 1, 7, 4, 7],
+        '3' => [7, 1, 7, 1, 7],
+        '4' => [5, 5, 7, 1, 1],
+        '5' => [7, 4, 7, 1, 7],
+        '6' => [7, 4, 7, 5, 7],
+        '7' => [7, 1, 2, 2, 2],
+        '8' => [7, 5, 7, 5, 7],
+        '9' => [7, 5, 7, 1, 7],
+        'A' => [2, 5, 7, 5, 5],
+        'B' => [6, 5, 6, 5, 6],
+        'C' => [3, 4, 4, 4, 3],
+        'D' => [6, 5, 5, 5, 6],
+        'E' => [7, 4, 6, 4, 7],
+        'F' => [7, 4, 6, 4, 4],
+        'G' => [3, 4, 5, 5, 3],
+        'H' => [5, 5, 7, 5, 5],
+        'I' => [7, 2, 2, 2, 7],
+        'J' => [1, 1, 1, 5, 2],
+        'K' => [5, 5, 6, 5, 5],
+        'L' => [4, 4, 4, 4, 7],
+        'M' => [5, 7, 7, 5, 5],
+        'N' => [6, 5, 5, 5, 5],
+        'O' => [2, 5, 5, 5, 2],
+        'P' => [6, 5, 6, 4, 4],
+        'Q' => [2, 5, 5, 6, 3],
+        'R' => [6, 5, 6, 5, 5],
+        'S' => [3, 4, 2, 1, 6],
+        'T' => [7, 2, 2, 2, 2],
+        'U' => [5, 5, 5, 5, 7],
+        'V' => [5, 5, 5, 5, 2],
+        'W' => [5, 5, 7, 7, 5],
+        'X' => [5, 5, 2, 5, 5],
+        'Y' => [5, 5, 2, 2, 2],
+        'Z' => [7, 1, 2, 4, 7],
+        ',' => [0, 0, 0, 2, 4],
+        '[' => [6, 4, 4, 4, 6],
+        ']' => [3, 1, 1, 1, 3],
+        ':' => [0, 2, 0, 2, 0],
+        '-' => [0, 0, 7, 0, 0],
+        '+' => [0, 2, 7, 2, 0],
+        '>' => [4, 2, 1, 2, 4],
+        _ => [0; 5],
+    }
+}
 
-            if sleep_duration >= 0 {
-                sleep(Duration::new(0, sleep_duration as u32));
+/// Create a grid texture covering the window with one cell per Chip8 pixel in a display of width x height
+fn create_grid<'a>(
+    canvas: &mut Canvas<Window>,
+    texture_creator: &'a TextureCreator<WindowContext>,
+    background_color: Color,
+    (window_width, window_height): (usize, usize),
+    (width, height): (usize, usize),
+) -> Texture<'a> {
+    let cell = (window_width / width).max(1);
+
+    let mut grid = texture_creator
+        .create_texture_target(
+            PixelFormatEnum::ARGB8888,
+            window_width as u32,
+            window_height as u32,
+        )
+        .unwrap();
+    grid.set_blend_mode(BlendMode::Blend);
+
+    canvas
+        .with_texture_canvas(&mut grid, |c| {
+            c.set_draw_color(Color::RGBA(0, 0, 0, 0));
+            c.clear();
+            let mut grid_color = background_color;
+            grid_color.a = 0x1d;
+            c.set_draw_color(grid_color);
+            // Draw horizontal lines
+            for y in (0..height).map(|y| y * cell) {
+                c.draw_line((0, y as i32), (window_width as i32, y as i32))
+                    .unwrap();
+            }
+            // Draw vertical lines
+            for x in (0..width).map(|x| x * cell) {
+                c.draw_line((x as i32, 0), (x as i32, window_height as i32))
+                    .unwrap();
             }
+        })
+        .unwrap();
+
+    grid
+}
+
+/// Sound output to an SDL2 audio device
+struct SdlAudio {
+    device: AudioDevice<PatternPlayer>,
+    /// Pitch of buzzer
+    pitch: u16,
+}
+
+impl AudioSink for SdlAudio {
+    fn play(&mut self, chip8: &Chip8) {
+        // Play the audio pattern of the Chip8 if loaded (XO-CHIP), otherwise a buzzer at the configured pitch
+        let (pattern, rate) = match chip8.pattern {
+            Some(pattern) => (pattern, chip8.pattern_rate()),
+            None => (BUZZER_PATTERN, self.pitch as f32 * PATTERN_BITS),
+        };
+        self.device.lock().set(pattern, rate);
+
+        if self.device.status() != AudioStatus::Playing {
+            self.device.resume();
+        }
+    }
+
+    fn pause(&mut self) {
+        if self.device.status() != AudioStatus::Paused {
+            self.device.pause();
+        }
+    }
+}
+
+/// Number of save state slots
+const STATE_SLOTS: u8 = 10;
+
+/// Keyboard and game controller input from SDL2 events
+struct SdlInput {
+    events: EventPump,
+    /// Selected save state slot
+    slot: u8,
+    /// Host keys of each Chip8 key
+    keys: Vec<Vec<Scancode>>,
+    /// None if SDL2 was built without game controller support
+    controller_subsystem: Option<GameControllerSubsystem>,
+    /// Connected game controllers by joystick id
+    controllers: HashMap<u32, GameController>,
+    /// Game controller inputs of each Chip8 key
+    buttons: Vec<Vec<ControllerInput>>,
+    /// Axis position below which an analog stick counts as centered
+    deadzone: i16,
+}
+
+/// A button or axis direction of a game controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ControllerInput {
+    Button(Button),
+    /// An axis pushed in the positive (true) or negative (false) direction
+    Axis(Axis, bool),
+}
+
+impl ControllerInput {
+    /// Parse a button name like "a" or "dpup", or an axis direction like "leftx-" or "lefttrigger+"
+    fn from_name(name: &str) -> Option<ControllerInput> {
+        if let Some(axis) = name.strip_suffix('+') {
+            Axis::from_string(axis).map(|axis| ControllerInput::Axis(axis, true))
+        } else if let Some(axis) = name.strip_suffix('-') {
+            Axis::from_string(axis).map(|axis| ControllerInput::Axis(axis, false))
+        } else {
+            Button::from_string(name).map(ControllerInput::Button)
         }
     }
 
-    fn keymap(&self, scancode: Scancode) -> Option<usize> {
-        match scancode {
-            Scancode::Num1 => Some(1),
-            Scancode::Num2 => Some(2),
-            Scancode::Num3 => Some(3),
-            Scancode::Num4 => Some(0xC),
-            Scancode::Q => Some(4),
-            Scancode::W => Some(5),
-            Scancode::E => Some(6),
-            Scancode::R => Some(0xD),
-            Scancode::A => Some(7),
-            Scancode::S => Some(8),
-            Scancode::D => Some(9),
-            Scancode::F => Some(0xE),
-            Scancode::Z => Some(0xA),
-            Scancode::X => Some(0),
-            Scancode::C => Some(0xB),
-            Scancode::V => Some(0xF),
-            _ => None,
+    /// The input is active on the controller
+    fn is_active(&self, controller: &GameController, deadzone: i16) -> bool {
+        match *self {
+            ControllerInput::Button(button) => controller.button(button),
+            ControllerInput::Axis(axis, true) => controller.axis(axis) > deadzone,
+            ControllerInput::Axis(axis, false) => controller.axis(axis) < deadzone.saturating_neg(),
+        }
+    }
+}
+
+impl InputSource for SdlInput {
+    fn poll(&mut self, keyboard: &mut [bool; KEYBOARD_SIZE]) -> Control {
+        for event in self.events.poll_iter() {
+            match event {
+                // Quit
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => return Control::Quit,
+                // Debugger
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => return Control::TogglePause,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    ..
+                } => return Control::Step,
+                // Speed
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    repeat: false,
+                    ..
+                } => return Control::Slower,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    repeat: false,
+                    ..
+                } => return Control::Faster,
+                // Save states
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => return Control::SaveState(self.slot),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    repeat: false,
+                    ..
+                } => {
+                    self.slot = (self.slot + 1) % STATE_SLOTS;
+                    println!("Save state slot {}", self.slot);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    repeat: false,
+                    ..
+                } => return Control::LoadState(self.slot),
+                // Game controllers plugged in and out
+                Event::ControllerDeviceAdded { which, .. } => {
+                    let Some(subsystem) = &self.controller_subsystem else {
+                        continue;
+                    };
+                    match subsystem.open(which) {
+                        Ok(controller) => {
+                            println!("Game controller connected: {}", controller.name());
+                            self.controllers
+                                .insert(controller.instance_id(), controller);
+                        }
+                        Err(error) => eprintln!("Error: could not open game controller: {}", error),
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(controller) = self.controllers.remove(&which) {
+                        println!("Game controller disconnected: {}", controller.name());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // A Chip8 key is down while any of its host keys is held or any of its controller inputs is active
+        let state = self.events.keyboard_state();
+        for (key, (scancodes, buttons)) in self.keys.iter().zip(&self.buttons).enumerate() {
+            let pressed = scancodes
+                .iter()
+                .any(|scancode| state.is_scancode_pressed(*scancode))
+                || self.controllers.values().any(|controller| {
+                    buttons
+                        .iter()
+                        .any(|button| button.is_active(controller, self.deadzone))
+                });
+            if pressed != keyboard[key] {
+                keyboard[key] = pressed;
+                #[cfg(debug_assertions)]
+                eprintln!("Key {:0x} {}", key, if pressed { "down" } else { "up" });
+            }
+        }
+
+        // Rewind while the key is held down
+        if self
+            .events
+            .keyboard_state()
+            .is_scancode_pressed(Scancode::Backspace)
+        {
+            return Control::Rewind;
         }
+
+        Control::Continue
     }
 }
 
-struct SquareWave {
+/// Scancodes of the host keys of each Chip8 key. Keys are looked up by name in the current keyboard layout
+fn scancodes(keymap: &Keymap) -> Vec<Vec<Scancode>> {
+    keymap
+        .keys
+        .iter()
+        .map(|hosts| {
+            hosts
+                .iter()
+                .filter_map(|name| {
+                    let scancode = Keycode::from_name(name).and_then(Scancode::from_keycode);
+                    if scancode.is_none() {
+                        eprintln!("Warning: unknown key {} in keymap", name);
+                    }
+                    scancode
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Game controller inputs of each Chip8 key
+fn controller_inputs(keymap: &Keymap) -> Vec<Vec<ControllerInput>> {
+    keymap
+        .buttons
+        .iter()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| {
+                    let input = ControllerInput::from_name(name);
+                    if input.is_none() {
+                        eprintln!("Warning: unknown game controller input {} in keymap", name);
+                    }
+                    input
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Convert a color in format ARGB8888 to an SDL color
+fn argb(color: u32) -> Color {
+    Color::RGBA(
+        ((color & 0xff0000) >> 16) as u8,
+        ((color & 0x00ff00) >> 8) as u8,
+        (color & 0x0000ff) as u8,
+        ((color & 0xff000000) >> 24) as u8,
+    )
+}
+
+/// Number of bits in an audio pattern
+const PATTERN_BITS: f32 = (AUDIO_PATTERN_SIZE * 8) as f32;
+/// Audio pattern of one period of a square wave used as buzzer
+const BUZZER_PATTERN: [u8; AUDIO_PATTERN_SIZE] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Plays a 1-bit audio pattern in a loop at a rate given in bits per second
+struct PatternPlayer {
+    pattern: [u8; AUDIO_PATTERN_SIZE],
+    freq: f32,
     phase_inc: f32,
     phase: f32,
     volume: f32,
 }
 
-impl AudioCallback for SquareWave {
+impl PatternPlayer {
+    /// Set the pattern and its playback rate in bits per second
+    fn set(&mut self, pattern: [u8; AUDIO_PATTERN_SIZE], rate: f32) {
+        self.pattern = pattern;
+        self.phase_inc = rate / self.freq;
+    }
+}
+
+impl AudioCallback for PatternPlayer {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
+        // Generate a wave from the bits of the pattern
         for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
+            let bit = self.phase as usize;
+            *x = if (self.pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1 {
                 self.volume
             } else {
                 -self.volume
             };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            self.phase = (self.phase + self.phase_inc) % PATTERN_BITS;
         }
     }
 }
@@ -0,0 +1,435 @@
+//! A stub for the GDB remote serial protocol, letting GDB and compatible tools debug a running Chip8 over a local TCP socket
+//!
+//! The registers are V0-VF (8 bits), I (16 bits), PC (16 bits) and SP (8 bits) in that order, little endian.
+//! The register layout is described to the client with a target description.
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+};
+
+use crate::{
+    chip8::{Chip8, NUMBER_OF_REGISTERS},
+    debugger::{Debugger, Stop},
+    machine::{Control, RemoteDebugger},
+};
+
+/// Target description of the Chip8 registers
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8rs.cpu">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+/// Register number of I
+const REGISTER_I: usize = NUMBER_OF_REGISTERS;
+/// Register number of PC
+const REGISTER_PC: usize = NUMBER_OF_REGISTERS + 1;
+/// Register number of SP
+const REGISTER_SP: usize = NUMBER_OF_REGISTERS + 2;
+
+/// Signal reported when stopped by a breakpoint, watchpoint or step
+const SIGTRAP: u8 = 5;
+/// Signal reported when stopped by an interrupt from the client
+const SIGINT: u8 = 2;
+
+/// A GDB remote serial protocol server for one client at a time
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    /// Received bytes not yet handled
+    buffer: Vec<u8>,
+    /// The client waits for a stop reply after continue or step
+    running: bool,
+}
+
+impl GdbStub {
+    /// Listen for a client on a port on localhost
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(GdbStub {
+            listener,
+            client: None,
+            buffer: Vec::new(),
+            running: false,
+        })
+    }
+
+    /// Accept a new client if none is connected. The Chip8 is paused when a client attaches
+    fn accept(&mut self, debugger: &mut Debugger) {
+        if let Ok((stream, address)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                eprintln!("GDB client connected from {}", address);
+                self.client = Some(stream);
+                self.buffer.clear();
+                self.running = false;
+                debugger.pause(Stop::Paused);
+            }
+        }
+    }
+
+    /// Read available bytes from the client. Returns false if the client is gone
+    fn receive(&mut self) -> bool {
+        let Some(client) = self.client.as_mut() else {
+            return false;
+        };
+        let mut bytes = [0; 1024];
+
+        loop {
+            match client.read(&mut bytes) {
+                Ok(0) => return false,
+                Ok(n) => self.buffer.extend_from_slice(&bytes[..n]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return true,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Send a packet with checksum to the client
+    fn send(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+
+        #[cfg(debug_assertions)]
+        eprintln!("GDB <- {}", packet);
+
+        if let Some(client) = self.client.as_mut() {
+            // The socket is non-blocking, but packets are small. A failing client is dropped on the next receive
+            let _ = write_all(client, packet.as_bytes());
+        }
+    }
+
+    /// Take the next complete packet from the buffer. Acknowledges it and handles interrupts
+    fn next_packet(&mut self, debugger: &mut Debugger) -> Option<String> {
+        loop {
+            let start = self.buffer.iter().position(|&b| b == b'$' || b == 0x03)?;
+
+            // Interrupt (Ctrl-C) outside of a packet
+            if self.buffer[start] == 0x03 {
+                self.buffer.drain(..=start);
+                if !debugger.paused {
+                    debugger.pause(Stop::Paused);
+                }
+                continue;
+            }
+
+            let end = start + self.buffer[start..].iter().position(|&b| b == b'#')?;
+            if self.buffer.len() < end + 3 {
+                return None;
+            }
+
+            let data = String::from_utf8_lossy(&self.buffer[start + 1..end]).into_owned();
+            let checksum = std::str::from_utf8(&self.buffer[end + 1..end + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            self.buffer.drain(..end + 3);
+
+            let valid = checksum == Some(data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b)));
+            if let Some(client) = self.client.as_mut() {
+                let _ = write_all(client, if valid { b"+" } else { b"-" });
+            }
+
+            if valid {
+                #[cfg(debug_assertions)]
+                eprintln!("GDB -> {}", data);
+
+                return Some(data);
+            }
+        }
+    }
+
+    /// Handle a packet and return the reply, if any
+    fn handle(
+        &mut self,
+        packet: &str,
+        chip8: &mut Chip8,
+        debugger: &mut Debugger,
+    ) -> (Option<String>, Control) {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => stop_reply(debugger.stop),
+            "g" => read_registers(chip8),
+            "G" => match write_registers(chip8, args) {
+                Some(()) => "OK".to_string(),
+                None => "E01".to_string(),
+            },
+            "p" => match parse_hex(args).and_then(|n| read_register(chip8, n)) {
+                Some(value) => value,
+                None => "E01".to_string(),
+            },
+            "P" => match args
+                .split_once('=')
+                .and_then(|(n, value)| write_register(chip8, parse_hex(n)?, value))
+            {
+                Some(()) => "OK".to_string(),
+                None => "E01".to_string(),
+            },
+            "m" => match parse_range(args).and_then(|(address, len)| {
+                chip8.memory()[..chip8.platform.memory_size()]
+                    .get(address..address.checked_add(len)?)
+                    .map(encode)
+            }) {
+                Some(hex) => hex,
+                None => "E01".to_string(),
+            },
+            "M" => match args.split_once(':').and_then(|(range, hex)| {
+                let (address, len) = parse_range(range)?;
+                let bytes = decode(hex).filter(|bytes| bytes.len() == len)?;
+                chip8
+                    .memory_mut()
+                    .get_mut(address..address.checked_add(len)?)?
+                    .copy_from_slice(&bytes);
+                Some(())
+            }) {
+                Some(()) => "OK".to_string(),
+                None => "E01".to_string(),
+            },
+            "Z" | "z" => match parse_breakpoint(args) {
+                Some(address) => {
+                    if command == "Z" {
+                        debugger.breakpoints.insert(address);
+                    } else {
+                        debugger.breakpoints.remove(&address);
+                    }
+                    "OK".to_string()
+                }
+                // Only software and hardware breakpoints are supported
+                None => String::new(),
+            },
+            "c" | "s" => {
+                if let Some(address) = parse_hex(args) {
+                    chip8.set_pc(address);
+                }
+                if command == "c" {
+                    debugger.resume();
+                } else {
+                    debugger.step();
+                }
+                self.running = true;
+                return (None, Control::Continue);
+            }
+            "D" => {
+                self.send("OK");
+                self.detach(debugger);
+                return (None, Control::Continue);
+            }
+            "k" => return (None, Control::Quit),
+            "H" => "OK".to_string(),
+            "q" if args.starts_with("Supported") => {
+                "PacketSize=4000;qXfer:features:read+".to_string()
+            }
+            "q" if args == "Attached" => "1".to_string(),
+            "q" if args == "C" => "QC1".to_string(),
+            "q" if args == "fThreadInfo" => "m1".to_string(),
+            "q" if args == "sThreadInfo" => "l".to_string(),
+            "q" if args.starts_with("Xfer:features:read:target.xml:") => {
+                transfer(TARGET_XML, &args["Xfer:features:read:target.xml:".len()..])
+            }
+            _ => String::new(),
+        };
+
+        (Some(reply), Control::Continue)
+    }
+
+    /// Let the Chip8 run freely and drop the client
+    fn detach(&mut self, debugger: &mut Debugger) {
+        eprintln!("GDB client disconnected");
+        self.client = None;
+        self.running = false;
+        if debugger.paused {
+            debugger.resume();
+        }
+    }
+}
+
+impl RemoteDebugger for GdbStub {
+    fn serve(&mut self, chip8: &mut Chip8, debugger: &mut Debugger) -> Control {
+        if self.client.is_none() {
+            self.accept(debugger);
+        }
+
+        if self.client.is_none() {
+            return Control::Continue;
+        }
+
+        if !self.receive() {
+            self.detach(debugger);
+            return Control::Continue;
+        }
+
+        while let Some(packet) = self.next_packet(debugger) {
+            let (reply, control) = self.handle(&packet, chip8, debugger);
+            if let Some(reply) = reply {
+                self.send(&reply);
+            }
+            if control == Control::Quit {
+                return control;
+            }
+        }
+
+        // Report when a continue or step has stopped. A pending single step has not been executed yet
+        if self.running && debugger.paused && debugger.steps == 0 {
+            self.running = false;
+            let reply = stop_reply(debugger.stop);
+            self.send(&reply);
+        }
+
+        Control::Continue
+    }
+
+    fn exited(&mut self) {
+        if self.client.is_some() {
+            self.send("W00");
+            self.running = false;
+        }
+    }
+}
+
+/// Write all bytes to a non-blocking stream, retrying while it would block
+fn write_all(stream: &mut TcpStream, mut bytes: &[u8]) -> io::Result<()> {
+    while !bytes.is_empty() {
+        match stream.write(bytes) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => bytes = &bytes[n..],
+            Err(error)
+                if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(())
+}
+
+/// Stop reply with the signal for why execution stopped
+fn stop_reply(stop: Option<Stop>) -> String {
+    let signal = match stop {
+        Some(Stop::Paused) | None => SIGINT,
+        Some(_) => SIGTRAP,
+    };
+
+    format!("S{:02x}", signal)
+}
+
+/// All registers as hex
+fn read_registers(chip8: &Chip8) -> String {
+    (0..=REGISTER_SP)
+        .filter_map(|n| read_register(chip8, n))
+        .collect()
+}
+
+/// Set all registers from hex
+fn write_registers(chip8: &mut Chip8, hex: &str) -> Option<()> {
+    let bytes = decode(hex)?;
+    let (v, rest) = bytes.split_at_checked(NUMBER_OF_REGISTERS)?;
+    let [i0, i1, pc0, pc1, sp] = rest[..] else {
+        return None;
+    };
+
+    chip8.registers_mut().copy_from_slice(v);
+    chip8.set_i(u16::from_le_bytes([i0, i1]) as usize);
+    chip8.set_pc(u16::from_le_bytes([pc0, pc1]) as usize);
+    chip8.set_sp(sp as usize);
+
+    Some(())
+}
+
+/// A register as hex in target byte order
+fn read_register(chip8: &Chip8, n: usize) -> Option<String> {
+    match n {
+        REGISTER_I => Some(encode(&(chip8.i() as u16).to_le_bytes())),
+        REGISTER_PC => Some(encode(&(chip8.pc() as u16).to_le_bytes())),
+        REGISTER_SP => Some(encode(&[chip8.sp() as u8])),
+        _ => chip8.registers().get(n).map(|v| encode(&[*v])),
+    }
+}
+
+/// Set a register from hex in target byte order
+fn write_register(chip8: &mut Chip8, n: usize, hex: &str) -> Option<()> {
+    let bytes = decode(hex)?;
+
+    match (n, &bytes[..]) {
+        (REGISTER_I, [b0, b1]) => chip8.set_i(u16::from_le_bytes([*b0, *b1]) as usize),
+        (REGISTER_PC, [b0, b1]) => chip8.set_pc(u16::from_le_bytes([*b0, *b1]) as usize),
+        (REGISTER_SP, [sp]) => chip8.set_sp(*sp as usize),
+        (n, [v]) if n < NUMBER_OF_REGISTERS => chip8.registers_mut()[n] = *v,
+        _ => return None,
+    }
+
+    Some(())
+}
+
+/// Part of a document requested with qXfer as "offset,length"
+fn transfer(document: &str, args: &str) -> String {
+    let Some((offset, len)) = parse_range(args) else {
+        return "E01".to_string();
+    };
+    let part = document
+        .get(offset.min(document.len())..)
+        .unwrap_or_default();
+
+    if part.len() > len {
+        format!("m{}", &part[..len])
+    } else {
+        format!("l{}", part)
+    }
+}
+
+/// Address of a breakpoint given as "type,address,kind". Only software (0) and hardware (1) breakpoints are supported
+fn parse_breakpoint(args: &str) -> Option<usize> {
+    let mut fields = args.split(',');
+    match fields.next()? {
+        "0" | "1" => parse_hex(fields.next()?),
+        _ => None,
+    }
+}
+
+/// Address and length given as "address,length" in hex
+fn parse_range(args: &str) -> Option<(usize, usize)> {
+    let (address, len) = args.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(len)?))
+}
+
+/// Number given in hex
+fn parse_hex(hex: &str) -> Option<usize> {
+    usize::from_str_radix(hex, 16).ok()
+}
+
+/// Bytes as hex
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex as bytes
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,315 @@
+//! Running a Chip8 without display, audio or keyboard, e.g. for scripted and CI execution
+use std::{error::Error, fmt, str::FromStr};
+
+use crate::{
+    chip8::{Chip8, Chip8Error, KEYBOARD_SIZE},
+    machine::{AudioSink, Control, FrameOutcome, InputSource, Machine, VideoSink},
+};
+
+/// The outcome of a headless run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadlessOutcome {
+    /// The program exited before the frame limit
+    Completed,
+    /// The program stopped with an error
+    Error(Chip8Error),
+    /// The frame limit was reached
+    TimedOut,
+}
+
+impl HeadlessOutcome {
+    /// Process exit code for the outcome. Timeout uses 124 like the timeout command
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            HeadlessOutcome::Completed => 0,
+            HeadlessOutcome::Error(_) => 1,
+            HeadlessOutcome::TimedOut => 124,
+        }
+    }
+}
+
+/// Video output that discards the display
+pub struct NullVideo;
+
+impl VideoSink for NullVideo {
+    fn present(&mut self, _chip8: &Chip8) {}
+}
+
+/// Audio output that discards the sound
+pub struct NullAudio;
+
+impl AudioSink for NullAudio {
+    fn play(&mut self, _chip8: &Chip8) {}
+    fn pause(&mut self) {}
+}
+
+/// A key press or release at a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptEvent {
+    /// Frame number (starting at 0) before which the event is applied
+    pub frame: u64,
+    /// Key number
+    pub key: usize,
+    /// Pressed (down) or released (up)
+    pub pressed: bool,
+}
+
+impl FromStr for ScriptEvent {
+    type Err = String;
+
+    /// Parse an event as `<frame> <key> <down|up>` with key as a hex digit
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [frame, key, action] = fields[..] else {
+            return Err("expected <frame> <key> <down|up>".to_string());
+        };
+        let frame = frame.parse().map_err(|_| "invalid frame number")?;
+        let key = usize::from_str_radix(key, 16)
+            .ok()
+            .filter(|key| *key < KEYBOARD_SIZE)
+            .ok_or("invalid key, expected 0-F")?;
+        let pressed = match action {
+            "down" => true,
+            "up" => false,
+            _ => return Err("invalid action, expected down or up".to_string()),
+        };
+
+        Ok(ScriptEvent {
+            frame,
+            key,
+            pressed,
+        })
+    }
+}
+
+/// Timed keyboard input. One event per line as `<frame> <key> <down|up>` with key as a hex digit. Lines starting with # are comments
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputScript {
+    /// Events sorted by frame
+    events: Vec<ScriptEvent>,
+    /// Index of the next event to apply
+    next: usize,
+    /// Number of frames polled
+    frame: u64,
+}
+
+/// An error in an input script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    /// Line number starting at 1
+    pub line: usize,
+    /// What is wrong
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ScriptError {}
+
+impl FromStr for InputScript {
+    type Err = ScriptError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut events: Vec<ScriptEvent> = Vec::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let error = |message: &str| ScriptError {
+                line: index + 1,
+                message: message.to_string(),
+            };
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            events.push(line.parse().map_err(|message: String| error(&message))?);
+        }
+
+        events.sort_by_key(|event| event.frame);
+
+        Ok(InputScript {
+            events,
+            next: 0,
+            frame: 0,
+        })
+    }
+}
+
+impl InputSource for InputScript {
+    fn poll(&mut self, keyboard: &mut [bool; KEYBOARD_SIZE]) -> Control {
+        while let Some(event) = self.events.get(self.next) {
+            if event.frame > self.frame {
+                break;
+            }
+            keyboard[event.key] = event.pressed;
+            self.next += 1;
+        }
+
+        self.frame += 1;
+
+        Control::Continue
+    }
+}
+
+/// Run the machine as fast as possible for at most frames frames with input from the input source
+pub fn run_headless(
+    machine: &mut Machine,
+    frames: u64,
+    input: &mut dyn InputSource,
+) -> HeadlessOutcome {
+    let mut outcome = HeadlessOutcome::TimedOut;
+
+    for _ in 0..frames {
+        match machine.frame(&mut NullVideo, &mut NullAudio, input) {
+            Ok(FrameOutcome::Continue | FrameOutcome::Paused) => {}
+            Ok(_) => {
+                outcome = HeadlessOutcome::Completed;
+                break;
+            }
+            Err(error) => {
+                outcome = HeadlessOutcome::Error(error);
+                break;
+            }
+        }
+    }
+
+    machine.flush_trace();
+
+    outcome
+}
+
+/// The display as a plain PBM image. A pixel is black if set in any plane
+pub fn display_to_pbm(chip8: &Chip8) -> String {
+    let (width, height) = (chip8.display_width(), chip8.display_height());
+    let mut pbm = format!("P1\n{} {}\n", width, height);
+
+    for row in chip8.display.iter().take(height) {
+        let line: Vec<&str> = row
+            .iter()
+            .take(width)
+            .map(|pixel| if *pixel != 0 { "1" } else { "0" })
+            .collect();
+        pbm.push_str(&line.join(" "));
+        pbm.push('\n');
+    }
+
+    pbm
+}
+
+/// The display as ASCII art. Pixels set in plane 1 are #, in plane 2 are + and in both planes are @
+pub fn display_to_ascii(chip8: &Chip8) -> String {
+    let (width, height) = (chip8.display_width(), chip8.display_height());
+    let mut ascii = String::new();
+
+    for row in chip8.display.iter().take(height) {
+        ascii.extend(row.iter().take(width).map(|pixel| match pixel {
+            0 => '.',
+            1 => '#',
+            2 => '+',
+            _ => '@',
+        }));
+        ascii.push('\n');
+    }
+
+    ascii
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Platform;
+
+    #[test]
+    fn input_script_parses_events() {
+        let script: InputScript = "# Start\n\n  5 a down\n2 F up\n5 A up\n".parse().unwrap();
+        let event = |frame, key, pressed| ScriptEvent {
+            frame,
+            key,
+            pressed,
+        };
+
+        // Sorted by frame, keeping the order within a frame
+        assert_eq!(
+            script.events,
+            [
+                event(2, 0xF, false),
+                event(5, 0xA, true),
+                event(5, 0xA, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn input_script_errors() {
+        let error = |script: &str| script.parse::<InputScript>().unwrap_err();
+
+        assert_eq!(
+            error("# Start\n-1 5 down"),
+            ScriptError {
+                line: 2,
+                message: "invalid frame number".to_string()
+            }
+        );
+        assert_eq!(error("1 G down").message, "invalid key, expected 0-F");
+        assert_eq!(error("1 10 down").message, "invalid key, expected 0-F");
+        assert_eq!(
+            error("1 5 pressed").message,
+            "invalid action, expected down or up"
+        );
+        assert_eq!(
+            error("\n\n1 5").to_string(),
+            "line 3: expected <frame> <key> <down|up>"
+        );
+    }
+
+    #[test]
+    fn input_script_applies_events_at_their_frame() {
+        let mut script: InputScript = "1 3 down\n2 3 up".parse().unwrap();
+        let mut keyboard = [false; KEYBOARD_SIZE];
+
+        let pressed: Vec<bool> = (0..3)
+            .map(|_| {
+                script.poll(&mut keyboard);
+                keyboard[3]
+            })
+            .collect();
+        assert_eq!(pressed, [false, true, false]);
+    }
+
+    #[test]
+    fn pbm_has_the_display_size() {
+        // Draws the top left pixel, in high resolution after HIGH
+        let lores = [0xA2, 0x06, 0xD0, 0x01, 0x00, 0x00, 0x80];
+        let hires = [0x00, 0xFF, 0xA2, 0x08, 0xD0, 0x01, 0x00, 0x00, 0x80];
+
+        for (program, steps, width, height) in [(&lores[..], 2, 64, 32), (&hires[..], 3, 128, 64)] {
+            let platform = Platform::SchipModern;
+            let mut chip8 = Chip8::load(program, 0x200, platform, platform.quirks()).unwrap();
+            for _ in 0..steps {
+                chip8.step().unwrap();
+            }
+
+            let pbm = display_to_pbm(&chip8);
+            let lines: Vec<&str> = pbm.lines().collect();
+            assert_eq!(lines[0], "P1");
+            assert_eq!(lines[1], format!("{} {}", width, height));
+            assert_eq!(lines.len(), 2 + height);
+            assert!(lines[2..]
+                .iter()
+                .all(|line| line.split(' ').count() == width));
+            assert!(lines[2].starts_with("1 0 "));
+            let set = lines[2..].iter().flat_map(|line| line.split(' '));
+            assert_eq!(set.filter(|pixel| *pixel == "1").count(), 1);
+
+            let ascii = display_to_ascii(&chip8);
+            assert_eq!(ascii.lines().count(), height);
+            assert!(ascii.lines().all(|line| line.len() == width));
+            assert!(ascii.starts_with("#."));
+        }
+    }
+}
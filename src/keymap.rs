@@ -0,0 +1,128 @@
+//! Mapping of host keys and game controller inputs to the keys of the Chip8 keyboard, with built-in profiles for common keyboard layouts
+use std::collections::BTreeMap;
+
+use crate::chip8::KEYBOARD_SIZE;
+
+/// Axis position (of 32767) below which an analog stick counts as centered
+pub const DEFAULT_DEADZONE: i16 = 8000;
+
+/// Host keys and game controller inputs of the Chip8 keys 0 to F
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    /// Keys named as in SDL, e.g. "Q", "Up", "Space" or "Keypad 8"
+    pub keys: [Vec<String>; KEYBOARD_SIZE],
+    /// Game controller buttons named as in SDL, e.g. "a", "start" or "dpup", and axis directions, e.g. "leftx-", "lefty+" or "righttrigger+"
+    pub buttons: [Vec<String>; KEYBOARD_SIZE],
+    /// Axis position below which an analog stick counts as centered
+    pub deadzone: i16,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            keys: Default::default(),
+            buttons: Default::default(),
+            deadzone: DEFAULT_DEADZONE,
+        }
+    }
+}
+
+impl Keymap {
+    /// Names of the built-in profiles
+    pub const PROFILES: [&'static str; 3] = ["qwerty", "azerty", "numpad"];
+
+    /// A built-in profile by name
+    pub fn profile(name: &str) -> Option<Keymap> {
+        // Host keys in the order of the Chip8 keypad: 1 2 3 C, 4 5 6 D, 7 8 9 E, A 0 B F
+        let keypad: [&str; KEYBOARD_SIZE] = match name {
+            "qwerty" => [
+                "1", "2", "3", "4", "Q", "W", "E", "R", "A", "S", "D", "F", "Z", "X", "C", "V",
+            ],
+            "azerty" => [
+                "1", "2", "3", "4", "A", "Z", "E", "R", "Q", "S", "D", "F", "W", "X", "C", "V",
+            ],
+            // Digits on the digits of the numpad, so 2, 4, 6 and 8 are the arrows games often use them as
+            "numpad" => [
+                "Keypad 1",
+                "Keypad 2",
+                "Keypad 3",
+                "Keypad -",
+                "Keypad 4",
+                "Keypad 5",
+                "Keypad 6",
+                "Keypad +",
+                "Keypad 7",
+                "Keypad 8",
+                "Keypad 9",
+                "Keypad Enter",
+                "Keypad /",
+                "Keypad 0",
+                "Keypad *",
+                "Keypad .",
+            ],
+            _ => return None,
+        };
+
+        // The directions of the D-pad and left stick, and the buttons A, B, X and Y.
+        // Games for keyboards like the numpad mostly move with 2, 4, 6 and 8, others with 5, 7, 8 and 9
+        let controller = if name == "numpad" {
+            [0x2, 0x8, 0x4, 0x6, 0x5, 0x0, 0xA, 0xB]
+        } else {
+            [0x5, 0x8, 0x7, 0x9, 0x6, 0x4, 0xE, 0xD]
+        };
+
+        let mut keymap = Keymap::default();
+        for (host, key) in keypad.iter().zip(KEYPAD) {
+            keymap.keys[key].push(host.to_string());
+        }
+        for (inputs, key) in CONTROLLER.iter().zip(controller) {
+            keymap.buttons[key].extend(inputs.iter().map(|input| input.to_string()));
+        }
+
+        Some(keymap)
+    }
+
+    /// Replace the host keys and game controller inputs of Chip8 keys given as hex digits, e.g. "5" = ["Up", "W"].
+    /// Other Chip8 keys are left as is
+    pub fn apply(
+        &mut self,
+        keys: &BTreeMap<String, Vec<String>>,
+        buttons: &BTreeMap<String, Vec<String>>,
+    ) -> Result<(), String> {
+        replace(&mut self.keys, keys)?;
+        replace(&mut self.buttons, buttons)
+    }
+}
+
+/// Replace the inputs of the Chip8 keys given as hex digits
+fn replace(
+    inputs: &mut [Vec<String>; KEYBOARD_SIZE],
+    overrides: &BTreeMap<String, Vec<String>>,
+) -> Result<(), String> {
+    for (key, names) in overrides {
+        let index = usize::from_str_radix(key, 16)
+            .ok()
+            .filter(|index| *index < KEYBOARD_SIZE)
+            .ok_or_else(|| format!("invalid Chip8 key {key}"))?;
+        inputs[index] = names.clone();
+    }
+
+    Ok(())
+}
+
+/// Game controller inputs of the built-in profiles: up, down, left, right, A, B, X and Y
+const CONTROLLER: [&[&str]; 8] = [
+    &["dpup", "lefty-"],
+    &["dpdown", "lefty+"],
+    &["dpleft", "leftx-"],
+    &["dpright", "leftx+"],
+    &["a"],
+    &["b"],
+    &["x"],
+    &["y"],
+];
+
+/// Chip8 keys in the order of the keypad
+const KEYPAD: [usize; KEYBOARD_SIZE] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
@@ -0,0 +1,42 @@
+//! A Chip8 emulator library
+//!
+//! The model for the Chip8 is in [chip8] and is independent of the framework used for input and output.
+//! Its random numbers come from a seedable source in [random].
+//! The frame scheduling in [machine] drives a frontend through the traits for video, audio and input.
+//! Instructions per frame are counted with [timing], optionally by the machine cycles of the COSMAC VIP.
+//! Execution can be paused, stepped and stopped at breakpoints and watchpoints with [debugger].
+//! GDB and compatible tools can debug a running Chip8 over the remote serial protocol with [gdb].
+//! Executed instructions can be written to a file with [trace] and counted with [profile].
+//! Going back in time frame by frame is done with [rewind].
+//! Programs can be assembled with [asm] and disassembled with [disasm].
+//! Keyboard input can be recorded and played back deterministically with [movie].
+//! Running without any frontend, e.g. in CI, is done with [headless].
+//! Host keys are mapped to the Chip8 keyboard with [keymap], configured per user and ROM in the file read by [config].
+//! The SDL2 frontend in [emusdl2] is available with the default feature `sdl`.
+pub mod asm;
+pub mod chip8;
+pub mod config;
+pub mod debugger;
+pub mod disasm;
+#[cfg(feature = "sdl")]
+pub mod emusdl2;
+pub mod gdb;
+pub mod headless;
+pub mod keymap;
+pub mod machine;
+pub mod movie;
+pub mod profile;
+pub mod random;
+pub mod rewind;
+pub mod timing;
+pub mod trace;
+
+pub use chip8::{
+    Chip8, Chip8Error, ErrorCause, Instruction, LoadError, Platform, Quirks, StateError,
+    StepOutcome, AUDIO_PATTERN_SIZE, DISPLAY_HEIGHT, DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT,
+    HIRES_DISPLAY_WIDTH, KEYBOARD_SIZE, NUMBER_OF_PLANES, PROGRAM_START,
+};
+pub use debugger::{Debugger, Stop, Watch, Watchpoint};
+pub use machine::{
+    AudioSink, Control, FrameOutcome, InputSource, Machine, RemoteDebugger, VideoSink,
+};
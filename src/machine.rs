@@ -0,0 +1,640 @@
+//! Frame scheduling of a Chip8 independent of the frontend used for input and output
+use std::{
+    path::PathBuf,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    chip8::{Chip8, Chip8Error, Instruction, StepOutcome, KEYBOARD_SIZE},
+    debugger::{Debugger, Stop},
+    movie::Movie,
+    profile::Profiler,
+    rewind::Rewind,
+    timing::{vip_cycles, Speed, Timing, VIP_CYCLES_PER_FRAME, VIP_INTERRUPT_CYCLES},
+    trace::Tracer,
+};
+
+/// Output of the Chip8 display
+pub trait VideoSink {
+    /// Present the display of the Chip8. Called whenever the display has been updated
+    fn present(&mut self, chip8: &Chip8);
+    /// Present the state of the Chip8 and the debugger. Called every display refresh while the debugger is enabled
+    fn present_debugger(&mut self, _chip8: &Chip8, _debugger: &Debugger) {}
+}
+
+/// Output of the Chip8 sound
+pub trait AudioSink {
+    /// Play the sound of the Chip8 (a buzzer or the audio pattern). Called every frame the sound timer is active
+    fn play(&mut self, chip8: &Chip8);
+    /// Stop playing. Called every frame the sound timer is inactive
+    fn pause(&mut self);
+}
+
+/// Input to the Chip8 keyboard
+pub trait InputSource {
+    /// Update the keyboard with the current state of the keys. Called once per frame
+    fn poll(&mut self, keyboard: &mut [bool; KEYBOARD_SIZE]) -> Control;
+}
+
+/// A debugger controlling the machine from outside, e.g. over a network protocol
+pub trait RemoteDebugger {
+    /// Serve requests to inspect and modify the Chip8 and to pause or resume it. Called once per frame before the Chip8 is stepped
+    fn serve(&mut self, chip8: &mut Chip8, debugger: &mut Debugger) -> Control;
+    /// The program has exited
+    fn exited(&mut self) {}
+}
+
+/// Control of the run loop from the input source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    /// Keep running
+    Continue,
+    /// The user wants to quit
+    Quit,
+    /// The user wants to pause or resume execution
+    TogglePause,
+    /// The user wants to execute a single instruction
+    Step,
+    /// The user wants to save the state in a numbered slot
+    SaveState(u8),
+    /// The user wants to load the state from a numbered slot
+    LoadState(u8),
+    /// The user wants to go back one frame in time
+    Rewind,
+    /// The user wants to run faster
+    Faster,
+    /// The user wants to run slower
+    Slower,
+}
+
+/// The outcome of running a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOutcome {
+    /// Keep running
+    Continue,
+    /// The user wants to quit
+    Quit,
+    /// The program has exited
+    Exit,
+    /// Execution is paused by the debugger
+    Paused,
+}
+
+/// Frames run per second. The timers are decremented once per frame
+pub const FRAMES_PER_SECOND: u32 = 60;
+
+/// Time the run loop catches up at most
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+/// A Chip8 run at 60 frames per second and a number of instructions per second, presented at any refresh rate
+pub struct Machine {
+    /// The Chip8 instance to run
+    pub chip8: Chip8,
+    /// Display refreshes per second. Independent of the 60 Hz the Chip8 runs at
+    pub fps: u16,
+    /// Instructions per second. Fractions of an instruction per frame are carried over to the next frame
+    pub ips: u32,
+    /// Count of instructions per frame. With VIP timing ips is not used
+    pub timing: Timing,
+    /// Speed relative to real time. Can be changed while running
+    pub speed: Speed,
+    /// Instructions carried over to the next frame in 60ths
+    budget: u64,
+    /// The display was changed during the current frame. Unlike display_update it doesn't depend on when the display was presented
+    drew: bool,
+    /// Machine cycles left in the current frame with VIP timing. Negative if the last instruction ran into the next frame
+    cycles: i64,
+    /// Number of frames run
+    pub frames: u64,
+    /// The sound timer was active during the last frame
+    pub sound: bool,
+    /// Pausing, stepping, breakpoints and watchpoints
+    pub debugger: Debugger,
+    /// Remote debugger served every frame if attached
+    pub remote: Option<Box<dyn RemoteDebugger>>,
+    /// Trace of executed instructions if enabled
+    pub tracer: Option<Tracer>,
+    /// Profile of executed instructions if enabled
+    pub profiler: Option<Profiler>,
+    /// Path of the program. Save states are stored next to it
+    pub program: Option<PathBuf>,
+    /// History of frames to rewind if enabled
+    pub rewind: Option<Rewind>,
+    /// Recording or playback of the keyboard if enabled
+    pub movie: Option<Movie>,
+}
+
+impl Machine {
+    /// Create a new machine running chip8 at ips instructions per second, presented at fps refreshes per second
+    pub fn new(chip8: Chip8, fps: u16, ips: u32) -> Self {
+        Machine {
+            chip8,
+            fps,
+            ips,
+            timing: Timing::default(),
+            speed: Speed::default(),
+            budget: 0,
+            drew: false,
+            cycles: 0,
+            frames: 0,
+            sound: false,
+            debugger: Debugger::default(),
+            remote: None,
+            tracer: None,
+            profiler: None,
+            program: None,
+            rewind: None,
+            movie: None,
+        }
+    }
+
+    /// Path of the save state in a slot, next to the program with extension state0, state1...
+    pub fn state_path(&self, slot: u8) -> Option<PathBuf> {
+        self.program
+            .as_ref()
+            .map(|program| program.with_extension(format!("state{}", slot)))
+    }
+
+    /// Save the state of the Chip8 in a slot
+    pub fn save_state(&self, slot: u8) {
+        let Some(path) = self.state_path(slot) else {
+            return;
+        };
+
+        match std::fs::write(&path, self.chip8.save_state()) {
+            Ok(()) => println!("Saved state to {}", path.display()),
+            Err(error) => eprintln!(
+                "Error: could not save state to {}: {}",
+                path.display(),
+                error
+            ),
+        }
+    }
+
+    /// Load the state of the Chip8 from a slot. The Chip8 is unchanged if the state can't be loaded
+    pub fn load_state(&mut self, slot: u8) {
+        let Some(path) = self.state_path(slot) else {
+            return;
+        };
+
+        let result = std::fs::read(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|state| {
+                self.chip8
+                    .load_state(&state)
+                    .map_err(|error| error.to_string())
+            });
+        match result {
+            Ok(()) => println!("Loaded state from {}", path.display()),
+            Err(error) => eprintln!(
+                "Error: could not load state from {}: {}",
+                path.display(),
+                error
+            ),
+        }
+    }
+
+    /// Write the records of the trace still buffered, if tracing. Called when the machine stops, as the process may exit without dropping it
+    pub fn flush_trace(&mut self) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Err(error) = tracer.flush() {
+                eprintln!("Error: could not write trace: {}", error);
+            }
+        }
+    }
+
+    /// Change the speed relative to real time
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        println!("Speed {}", speed);
+    }
+
+    /// Step the Chip8 once, tracing and profiling the instruction if enabled
+    fn step(&mut self) -> Result<StepOutcome, Chip8Error> {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(&self.chip8, self.frames);
+        }
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Err(error) = tracer.record(&self.chip8, self.frames) {
+                eprintln!("Error: could not write trace: {}", error);
+                self.tracer = None;
+            }
+        }
+
+        // display_update is left set until presented, so drawing is detected by clearing it for the step
+        let updated = self.chip8.display_update;
+        self.chip8.display_update = false;
+        let outcome = self.chip8.step();
+        self.drew |= self.chip8.display_update;
+        self.chip8.display_update |= updated;
+
+        outcome
+    }
+
+    /// Step the Chip8 for a frame as counted by the timing and decrement the timers. No input or output is done.
+    /// While paused by the debugger only the requested single steps are executed and time stands still
+    pub fn step_frame(&mut self) -> Result<FrameOutcome, Chip8Error> {
+        self.drew = false;
+
+        if self.debugger.paused {
+            while self.debugger.steps > 0 {
+                self.debugger.steps -= 1;
+                self.debugger.stop = Some(Stop::Stepped);
+                if self.step()? == StepOutcome::Exit {
+                    return Ok(FrameOutcome::Exit);
+                }
+            }
+
+            self.sound = false;
+
+            return Ok(FrameOutcome::Paused);
+        }
+
+        let outcome = match self.timing {
+            Timing::Fixed => self.step_fixed()?,
+            Timing::Vip => self.step_vip()?,
+        };
+        if outcome != FrameOutcome::Continue {
+            return Ok(outcome);
+        }
+
+        // Decrement delay timer if non-zero
+        if self.chip8.dt > 0 {
+            self.chip8.dt -= 1;
+        }
+
+        // Decrement sound timer if non-zero. Sound is played while it is active
+        self.sound = self.chip8.st > 0;
+        if self.chip8.st > 0 {
+            self.chip8.st -= 1;
+        }
+
+        self.frames += 1;
+
+        Ok(FrameOutcome::Continue)
+    }
+
+    /// Stop at a breakpoint or watchpoint if the debugger says so before stepping
+    fn check_debugger(&mut self) -> Option<FrameOutcome> {
+        let stop = self.debugger.check(&self.chip8)?;
+
+        self.debugger.pause(stop);
+        self.sound = false;
+
+        Some(FrameOutcome::Paused)
+    }
+
+    /// Step the Chip8 for the instructions per frame, or until drawing if the display_wait quirk is set
+    fn step_fixed(&mut self) -> Result<FrameOutcome, Chip8Error> {
+        // The budget is below 60 before adding, so it can't overflow in 64 bits
+        self.budget += self.ips as u64;
+        let instructions = self.budget / FRAMES_PER_SECOND as u64;
+        self.budget %= FRAMES_PER_SECOND as u64;
+
+        for _ in 0..instructions {
+            if let Some(outcome) = self.check_debugger() {
+                return Ok(outcome);
+            }
+
+            if self.step()? == StepOutcome::Exit {
+                return Ok(FrameOutcome::Exit);
+            }
+
+            if self.chip8.quirks.display_wait && self.drew {
+                break;
+            }
+        }
+
+        Ok(FrameOutcome::Continue)
+    }
+
+    /// Step the Chip8 for the machine cycles of a VIP frame left after the display interrupt.
+    /// Drawing waits for the interrupt, so a Draw is only executed as the first instruction of a frame
+    fn step_vip(&mut self) -> Result<FrameOutcome, Chip8Error> {
+        // Cycles beyond the last frame are carried over
+        self.cycles += VIP_CYCLES_PER_FRAME - VIP_INTERRUPT_CYCLES;
+        let mut first = true;
+
+        while self.cycles > 0 {
+            let Ok(opcode) = self.chip8.fetch() else {
+                // Stepping reports the error
+                self.step()?;
+                break;
+            };
+            let instr = Chip8::decode(opcode, self.chip8.platform);
+            if matches!(instr, Instruction::Draw(..)) && !first {
+                self.cycles = 0;
+                break;
+            }
+
+            if let Some(outcome) = self.check_debugger() {
+                self.cycles = 0;
+                return Ok(outcome);
+            }
+
+            self.cycles -= vip_cycles(&self.chip8, instr) as i64;
+            first = false;
+
+            if self.step()? == StepOutcome::Exit {
+                return Ok(FrameOutcome::Exit);
+            }
+        }
+
+        Ok(FrameOutcome::Continue)
+    }
+
+    /// Run one frame. Poll input, step the Chip8, play sound and present the display if updated
+    pub fn frame(
+        &mut self,
+        video: &mut dyn VideoSink,
+        audio: &mut dyn AudioSink,
+        input: &mut dyn InputSource,
+    ) -> Result<FrameOutcome, Chip8Error> {
+        let outcome = self.tick(audio, input)?;
+        self.present(video);
+
+        Ok(outcome)
+    }
+
+    /// Run one frame without presenting. Poll input, step the Chip8 and play sound
+    pub fn tick(
+        &mut self,
+        audio: &mut dyn AudioSink,
+        input: &mut dyn InputSource,
+    ) -> Result<FrameOutcome, Chip8Error> {
+        match input.poll(&mut self.chip8.keyboard) {
+            Control::Continue => {}
+            Control::Quit => return Ok(FrameOutcome::Quit),
+            Control::TogglePause => self.debugger.toggle_pause(),
+            // A movie only has the input of each frame, so anything else changing the state would make it diverge.
+            // Rewinding is held for a while, so it is ignored without an error every frame
+            Control::Rewind if self.movie.is_some() => {}
+            Control::Step | Control::LoadState(_) if self.movie.is_some() => {
+                eprintln!("Error: can't change the state while a movie is recording or playing")
+            }
+            Control::Step => self.debugger.step(),
+            Control::SaveState(slot) => self.save_state(slot),
+            Control::LoadState(slot) => self.load_state(slot),
+            Control::Faster => self.set_speed(self.speed.faster()),
+            Control::Slower => self.set_speed(self.speed.slower()),
+            Control::Rewind => {
+                // Go back one frame instead of running one. Sound is silent while rewinding
+                if let Some(rewind) = self.rewind.as_mut() {
+                    rewind.rewind(&mut self.chip8);
+                }
+                audio.pause();
+                return Ok(FrameOutcome::Continue);
+            }
+        }
+
+        let frame = self.frames;
+
+        if let Some(movie) = self.movie.as_mut() {
+            movie.input(frame, &mut self.chip8.keyboard);
+        }
+
+        if let Some(remote) = self.remote.as_mut() {
+            if remote.serve(&mut self.chip8, &mut self.debugger) == Control::Quit {
+                return Ok(FrameOutcome::Quit);
+            }
+        }
+
+        let outcome = self.step_frame()?;
+
+        if outcome == FrameOutcome::Continue {
+            if let Some(rewind) = self.rewind.as_mut() {
+                rewind.capture(&self.chip8);
+            }
+
+            if let (Some(movie), true) = (self.movie.as_mut(), self.drew) {
+                movie.display(frame, &self.chip8);
+            }
+        }
+
+        if outcome == FrameOutcome::Exit {
+            if let Some(remote) = self.remote.as_mut() {
+                remote.exited();
+            }
+        }
+
+        if self.sound {
+            audio.play(&self.chip8);
+        } else {
+            audio.pause();
+        }
+
+        Ok(outcome)
+    }
+
+    /// Present the display if updated and the debugger if enabled
+    pub fn present(&mut self, video: &mut dyn VideoSink) {
+        // Present display if Chip8 indicates display is updated
+        if self.chip8.display_update {
+            video.present(&self.chip8);
+            self.chip8.display_update = false; // Chip8 will set this to true whenever something changes on screen
+        }
+
+        if self.debugger.enabled {
+            video.present_debugger(&self.chip8, &self.debugger);
+        }
+    }
+
+    /// Run until the user quits, the program exits or an error occurs. Frames are run at 60 Hz times the speed and the display is presented at fps.
+    /// Elapsed time is accumulated, so frames missed because of a late wake up are caught up at the next refresh.
+    /// At unlimited speed frames are run until the next refresh is due
+    pub fn run(
+        &mut self,
+        video: &mut dyn VideoSink,
+        audio: &mut dyn AudioSink,
+        input: &mut dyn InputSource,
+    ) -> Result<FrameOutcome, Chip8Error> {
+        let frame_duration = Duration::from_secs(1) / FRAMES_PER_SECOND;
+        let refresh_duration = Duration::from_secs(1) / self.fps.max(1) as u32;
+        // Time that can be caught up at once. More is dropped, e.g. after the process was suspended
+        let max_lag = MAX_LAG.max(refresh_duration * 2);
+
+        let mut accumulated = Duration::ZERO;
+        let mut last = Instant::now();
+        let mut next_refresh = last + refresh_duration;
+
+        loop {
+            let speed = self.speed;
+            let now = Instant::now();
+            if let Some(factor) = speed.factor() {
+                accumulated =
+                    (accumulated + (now - last).mul_f64(factor)).min(max_lag.mul_f64(factor));
+            }
+            last = now;
+
+            loop {
+                let due = match speed {
+                    Speed::Unlimited => Instant::now() < next_refresh,
+                    _ => accumulated >= frame_duration,
+                };
+                if !due {
+                    break;
+                }
+                accumulated = accumulated.saturating_sub(frame_duration);
+
+                let outcome = self.tick(audio, input)?;
+                if !matches!(outcome, FrameOutcome::Continue | FrameOutcome::Paused) {
+                    self.present(video);
+                    return Ok(outcome);
+                }
+
+                // Time is accumulated at the new speed from now on
+                if self.speed != speed {
+                    accumulated = Duration::ZERO;
+                    break;
+                }
+            }
+
+            self.present(video);
+
+            // Refreshes are scheduled on a fixed grid. If one is missed completely the grid restarts from now
+            let now = Instant::now();
+            if next_refresh < now {
+                next_refresh = now;
+            }
+
+            sleep(next_refresh - now);
+            next_refresh += refresh_duration;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chip8::Platform,
+        headless::{NullAudio, NullVideo},
+        movie::MovieOptions,
+    };
+
+    /// Input giving a control on the first poll
+    struct Once(Option<Control>);
+
+    impl InputSource for Once {
+        fn poll(&mut self, _keyboard: &mut [bool; KEYBOARD_SIZE]) -> Control {
+            self.0.take().unwrap_or(Control::Continue)
+        }
+    }
+
+    /// A machine running a program of count times ADDB V0, 1 at ips instructions per second
+    fn machine(count: usize, ips: u32) -> Machine {
+        let program = [0x70, 0x01].repeat(count);
+        let platform = Platform::SchipModern;
+        let chip8 = Chip8::load(&program, 0x200, platform, platform.quirks()).unwrap();
+        Machine::new(chip8, 60, ips)
+    }
+
+    /// Number of instructions executed by the machine
+    fn executed(machine: &Machine) -> usize {
+        (machine.chip8.pc() - 0x200) / 2
+    }
+
+    #[test]
+    fn fractional_budget_adds_up_to_ips() {
+        let mut machine = machine(200, 100);
+        let mut per_frame = Vec::new();
+        for _ in 0..FRAMES_PER_SECOND {
+            let before = executed(&machine);
+            machine.step_frame().unwrap();
+            per_frame.push(executed(&machine) - before);
+        }
+
+        assert_eq!(executed(&machine), 100);
+        assert!(per_frame.iter().all(|n| (1..=2).contains(n)));
+    }
+
+    #[test]
+    fn huge_ips_does_not_overflow() {
+        let mut machine = machine(8, u32::MAX);
+        assert!(machine.step_frame().is_err());
+    }
+
+    #[test]
+    fn movie_refuses_state_changes() {
+        let mut machine = machine(200, 600);
+        let platform = machine.chip8.platform;
+        machine.movie = Some(Movie::record(MovieOptions {
+            rom: None,
+            platform,
+            load_address: 0x200,
+            quirks: machine.chip8.quirks,
+            seed: 0,
+            ips: 600,
+            timing: Timing::Fixed,
+        }));
+        machine.rewind = Some(Rewind::new(10));
+
+        // Rewinding runs the frame as usual instead of going back
+        machine
+            .frame(&mut NullVideo, &mut NullAudio, &mut Once(None))
+            .unwrap();
+        machine
+            .frame(
+                &mut NullVideo,
+                &mut NullAudio,
+                &mut Once(Some(Control::Rewind)),
+            )
+            .unwrap();
+        assert_eq!(machine.frames, 2);
+        assert_eq!(executed(&machine), 20);
+
+        // Stepping while paused is refused
+        machine.debugger.toggle_pause();
+        machine
+            .frame(
+                &mut NullVideo,
+                &mut NullAudio,
+                &mut Once(Some(Control::Step)),
+            )
+            .unwrap();
+        assert_eq!(executed(&machine), 20);
+
+        // Without a movie it steps
+        machine.movie = None;
+        machine
+            .frame(
+                &mut NullVideo,
+                &mut NullAudio,
+                &mut Once(Some(Control::Step)),
+            )
+            .unwrap();
+        assert_eq!(executed(&machine), 21);
+    }
+
+    #[test]
+    fn frames_run_at_60_hz_whatever_the_fps() {
+        // Sets the delay timer to 200, then draws digit 0 and increments V1 in a loop
+        let program = [
+            0x60, 0xC8, 0xF0, 0x15, 0x60, 0x00, 0xF0, 0x29, 0xD2, 0x25, 0x71, 0x01, 0x12, 0x08,
+        ];
+        let platform = Platform::CosmacVip;
+        assert!(platform.quirks().display_wait);
+
+        for fps in [30, 60, 144] {
+            let chip8 = Chip8::load(&program, 0x200, platform, platform.quirks()).unwrap();
+            let mut machine = Machine::new(chip8, fps, 600);
+            let mut input = Once(None);
+
+            // Present as often as the display refreshes in the time of each 60 Hz frame
+            for frame in 0..FRAMES_PER_SECOND {
+                machine.tick(&mut NullAudio, &mut input).unwrap();
+                let refreshes = (frame + 1) * fps as u32 / 60 - frame * fps as u32 / 60;
+                for _ in 0..refreshes {
+                    machine.present(&mut NullVideo);
+                }
+            }
+
+            assert_eq!(machine.frames, 60, "fps {}", fps);
+            assert_eq!(machine.chip8.dt, 200 - 60, "fps {}", fps);
+            // One draw per frame, the first frame draws before the first increment
+            assert_eq!(machine.chip8.registers()[1], 59, "fps {}", fps);
+        }
+    }
+}
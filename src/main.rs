@@ -1,30 +1,67 @@
-mod chip8;
-mod emusdl2;
+use std::{fs::File, io::BufWriter, ops::RangeInclusive, path::PathBuf, process};
 
-use std::path::PathBuf;
-
-use clap::Parser;
-use clap_num::maybe_hex;
-use emusdl2::EmuSdl2;
-
-use crate::{
-    chip8::{Chip8, Quirks},
-    emusdl2::Options,
+use chip8rs::{
+    asm::assemble_file,
+    config::{config_path, rom_id, Config, Settings},
+    disasm::{disassemble, Syntax},
+    headless::{display_to_ascii, display_to_pbm, run_headless, HeadlessOutcome, InputScript},
+    keymap::Keymap,
+    machine::FRAMES_PER_SECOND,
+    movie::{Movie, MovieMode, MovieOptions},
+    profile::Profiler,
+    random::SplitMix64,
+    timing::{Speed, Timing},
+    trace::{parse_address_range, parse_frame_range, TraceFormat, Tracer},
+    Chip8, Machine, Platform, Quirks, Watchpoint, PROGRAM_START,
+};
+#[cfg(feature = "sdl")]
+use chip8rs::{
+    emusdl2::{EmuSdl2, Options},
+    Debugger, Stop,
+};
+use clap::{
+    builder::PossibleValuesParser, builder::TypedValueParser, parser::ValueSource, ArgMatches,
+    CommandFactory, FromArgMatches, Parser, Subcommand,
 };
+use clap_num::maybe_hex;
 
 #[derive(Debug, Parser)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 /// A simple Chip8 emulator that uses SDL
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Path to the binary Chip8 program to run
-    program: PathBuf,
-    /// Frames per second
+    #[arg(required = true)]
+    program: Option<PathBuf>,
+    /// Platform to emulate. Sets quirks, memory size, display size and instructions per frame
+    #[arg(long, default_value_t = Platform::default(), value_parser = PossibleValuesParser::new(Platform::NAMES).map(|s| s.parse::<Platform>().unwrap()))]
+    platform: Platform,
+    /// Address where the program is loaded and started (e.g. 0x600 for ETI-660 programs)
+    #[arg(long, value_parser=maybe_hex::<usize>, default_value_t = PROGRAM_START)]
+    load_address: usize,
+    /// Display refreshes per second. The Chip8 and its timers always run at 60 frames per second
     #[arg(short, long, default_value_t = 60)]
     fps: u16,
-    /// Instruction multiplier (instructions per frame)
-    #[arg(short, long, default_value_t = 20)]
-    mul: u16,
-    /// Scale of display
+    /// Instruction multiplier (instructions per 60 Hz frame). Default depends on platform
+    #[arg(short, long, conflicts_with = "ips")]
+    mul: Option<u16>,
+    /// Instructions per second, e.g. 700. Default is mul times 60
+    #[arg(long)]
+    ips: Option<u32>,
+    /// Speed relative to real time. Can be changed while running with F2 (slower) and F3 (faster)
+    #[arg(long, default_value_t = Speed::default(), value_parser = PossibleValuesParser::new(Speed::NAMES).map(|s| s.parse::<Speed>().unwrap()))]
+    speed: Speed,
+    /// Timing of instructions. vip counts the machine cycles of each instruction on a COSMAC VIP instead of using ips
+    #[arg(long, default_value_t = Timing::default(), value_parser = PossibleValuesParser::new(Timing::NAMES).map(|s| s.parse::<Timing>().unwrap()))]
+    timing: Timing,
+    /// Scale of display (pixels per Chip8 pixel in the largest resolution of the platform)
     #[arg(short, long, default_value_t = 10)]
     scale: u8,
     /// Foreground color. Format ARGB8888 (hex possible, e.g. 0xff0000ff)
@@ -33,57 +70,610 @@ struct Cli {
     /// Background color. Format ARGB8888 (hex possible, e.g. 0xff111111)
     #[arg(short, long, value_parser=maybe_hex::<u32>, default_value_t = 0xff666f66)]
     background: u32,
+    /// Color of pixels only in plane 2 (XO-CHIP). Format ARGB8888
+    #[arg(long, value_parser=maybe_hex::<u32>, default_value_t = 0xffb0b8b0)]
+    plane2_color: u32,
+    /// Color of pixels in both planes (XO-CHIP). Format ARGB8888
+    #[arg(long, value_parser=maybe_hex::<u32>, default_value_t = 0xff444a44)]
+    overlap_color: u32,
     /// Pitch of buzzer in Hz
     #[arg(short, long, default_value_t = 432)]
     pitch: u16,
-    /// Quirk: AND, OR, XOR reset VF to zero
-    #[arg(long, default_value_t = true)]
-    quirk_vf_reset: bool,
-    /// Quirk: Memory load/store registers operations increment I
-    #[arg(long, default_value_t = true)]
-    quirk_memory: bool,
-    /// Quirk: Only one draw operation per frame
-    #[arg(long, default_value_t = true)]
-    quirk_display_wait: bool,
-    /// Quirk: Drawing operations clip instead of wrap
-    #[arg(long, default_value_t = true)]
-    quirk_clipping: bool,
-    /// Quirk: Shifting operations use VY instead of only VX
+    /// Quirk: AND, OR, XOR reset VF to zero. Default depends on platform
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    quirk_vf_reset: Option<bool>,
+    /// Quirk: Memory load/store registers operations increment I. Default depends on platform
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    quirk_memory: Option<bool>,
+    /// Quirk: Only one draw operation per frame. Default depends on platform
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    quirk_display_wait: Option<bool>,
+    /// Quirk: Drawing operations clip instead of wrap. Default depends on platform
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    quirk_clipping: Option<bool>,
+    /// Quirk: Shifting operations use only VX instead of VY. Default depends on platform
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    quirk_shifting: Option<bool>,
+    /// Quirk: Jump with offset operation BNNN will work as BXNN. Default depends on platform
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    quirk_jumping: Option<bool>,
+    /// Quirk: Wait for key operation FX0A takes a key as soon as it is held instead of when it is released. Default is false
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    quirk_key_press: Option<bool>,
+    /// Keymap profile: qwerty, azerty, numpad or a custom profile from the config file. Default is the profile configured for the ROM, the global one or qwerty
+    #[arg(long, value_name = "PROFILE")]
+    keymap: Option<String>,
+    /// Start paused in debugger mode with a panel showing the state of the Chip8. F9 pauses and continues, F10 steps a single instruction
+    #[arg(long)]
+    debug: bool,
+    /// Debugger: Stop when the program counter reaches the address. Can be given several times
+    #[arg(long = "break", value_name = "ADDRESS", value_parser=maybe_hex::<usize>)]
+    breakpoints: Vec<usize>,
+    /// Debugger: Stop before a memory address or register is read and/or written, e.g. "0x300:w", "V3:r" or "VF". Can be given several times
+    #[arg(long = "watch", value_name = "WATCHPOINT")]
+    watchpoints: Vec<Watchpoint>,
+    /// Serve the GDB remote serial protocol on the port on localhost. The program starts paused until a client continues it
+    #[arg(long, value_name = "PORT")]
+    gdb: Option<u16>,
+    /// Write a record of every executed instruction to the file
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+    /// Trace: Format of the records
+    #[arg(long, default_value_t = TraceFormat::default(), value_parser = PossibleValuesParser::new(TraceFormat::NAMES).map(|s| s.parse::<TraceFormat>().unwrap()))]
+    trace_format: TraceFormat,
+    /// Trace: Only instructions at addresses in the range, e.g. "0x200-0x2ff"
+    #[arg(long, value_name = "START-END", value_parser = parse_address_range)]
+    trace_addresses: Option<RangeInclusive<usize>>,
+    /// Trace: Only instructions in frames in the range, e.g. "60-120"
+    #[arg(long, value_name = "START-END", value_parser = parse_frame_range)]
+    trace_frames: Option<RangeInclusive<u64>>,
+    /// Count executed instructions per address, instruction, subroutine and frame and write a report to the file at exit
+    #[arg(long, value_name = "FILE")]
+    profile: Option<PathBuf>,
+    /// Profile: Number of addresses in the report
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    profile_top: usize,
+    /// Number of frames that can be rewound by holding backspace. 0 disables rewinding
+    #[arg(long, value_name = "FRAMES", default_value_t = 600)]
+    rewind_frames: usize,
+    /// Seed of the random number generator. The same seed gives the same random numbers. Default is a random seed
+    #[arg(long, value_parser=maybe_hex::<u64>)]
+    seed: Option<u64>,
+    /// Record the keyboard input and display to a movie file together with the options in use. Can't be used with the debugger
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["play", "debug", "breakpoints", "watchpoints", "gdb"])]
+    record: Option<PathBuf>,
+    /// Play back the keyboard input of a movie file with the options it was recorded with and report any divergence of the display.
+    /// Can't be used with the debugger
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["debug", "breakpoints", "watchpoints", "gdb"])]
+    play: Option<PathBuf>,
+    /// Print the options and quirks in effect after applying the config file and exit
+    #[arg(long)]
+    print_config: bool,
+    /// Run without display, audio and keyboard as fast as possible. Exit code is 0 if the program exits, 1 on error and 124 if the frame limit is reached
     #[arg(long)]
-    quirk_shifting: bool,
-    /// Quirk: Jump with offset operation BNNN will work as BXNN.
+    headless: bool,
+    /// Headless: Number of frames to run
+    #[arg(long, default_value_t = 600)]
+    frames: u64,
+    /// Headless: Input script with lines "<frame> <key> <down|up>", e.g. "60 5 down"
     #[arg(long)]
-    quirk_jumping: bool,
+    input: Option<PathBuf>,
+    /// Headless: Write the final display to a PBM file instead of stdout as ASCII art
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Disassemble a binary Chip8 program
+    Disasm {
+        /// Path to the binary Chip8 program to disassemble
+        program: PathBuf,
+        /// Syntax of the listing
+        #[arg(long, default_value_t = Syntax::default(), value_parser = PossibleValuesParser::new(Syntax::NAMES).map(|s| s.parse::<Syntax>().unwrap()))]
+        syntax: Syntax,
+        /// Platform of the program. Decides the instruction set
+        #[arg(long, default_value_t = Platform::default(), value_parser = PossibleValuesParser::new(Platform::NAMES).map(|s| s.parse::<Platform>().unwrap()))]
+        platform: Platform,
+        /// Address where the program is loaded and started
+        #[arg(long, value_parser=maybe_hex::<usize>, default_value_t = PROGRAM_START)]
+        load_address: usize,
+        /// Decode every word as an instruction instead of following the code from the start
+        #[arg(long)]
+        linear: bool,
+    },
+    /// Assemble a Chip8 source file into a binary program
+    Asm {
+        /// Path to the source file
+        source: PathBuf,
+        /// Path to the binary program. Default is the source path with extension ch8
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Address where the program will be loaded
+        #[arg(long, value_parser=maybe_hex::<usize>, default_value_t = PROGRAM_START)]
+        load_address: usize,
+    },
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+
+    if let Some(command) = &cli.command {
+        match command {
+            Command::Disasm {
+                program,
+                syntax,
+                platform,
+                load_address,
+                linear,
+            } => {
+                let program = read(program);
+                for line in disassemble(&program, *load_address, *platform, *syntax, *linear) {
+                    println!("{}", line);
+                }
+            }
+            Command::Asm {
+                source,
+                output,
+                load_address,
+            } => {
+                let binary = assemble_file(source, *load_address).unwrap_or_else(|error| {
+                    eprintln!("Error: {}", error);
+                    process::exit(1);
+                });
+                let output = output
+                    .clone()
+                    .unwrap_or_else(|| source.with_extension("ch8"));
+                std::fs::write(&output, binary).unwrap_or_else(|error| {
+                    eprintln!("Error: could not write {}: {}", output.display(), error);
+                    process::exit(1);
+                });
+            }
+        }
+        return;
+    }
+
+    let program = read(cli.program.as_ref().unwrap());
+
+    // Options not given on the command line are taken from the config file
+    let config = config();
+    configure(&mut cli, &matches, &config.settings(&program));
+
+    // A movie is played back with the options it was recorded with
+    let playback = cli.play.as_ref().map(read_movie);
+    if let Some(movie) = &playback {
+        let o = &movie.options;
+        if o.rom.as_ref().is_some_and(|rom| *rom != rom_id(&program)) {
+            eprintln!(
+                "Error: movie {} was recorded with another ROM",
+                cli.play.as_ref().unwrap().display()
+            );
+            process::exit(1);
+        }
+        cli.platform = o.platform;
+        cli.load_address = o.load_address;
+        cli.quirk_vf_reset = Some(o.quirks.vf_reset);
+        cli.quirk_memory = Some(o.quirks.memory);
+        cli.quirk_display_wait = Some(o.quirks.display_wait);
+        cli.quirk_clipping = Some(o.quirks.clipping);
+        cli.quirk_shifting = Some(o.quirks.shifting);
+        cli.quirk_jumping = Some(o.quirks.jumping);
+        cli.quirk_key_press = Some(o.quirks.key_press);
+        cli.seed = Some(o.seed);
+        cli.ips = Some(o.ips);
+        cli.timing = o.timing;
+        cli.frames = movie.frames;
+    }
+
+    // The seed is chosen here so it can be recorded
+    let seed = *cli.seed.get_or_insert_with(rand::random);
+
+    // Start with the quirks of the platform and override with any quirks given
+    let mut quirks = cli.platform.quirks();
+    quirks.vf_reset = cli.quirk_vf_reset.unwrap_or(quirks.vf_reset);
+    quirks.memory = cli.quirk_memory.unwrap_or(quirks.memory);
+    quirks.display_wait = cli.quirk_display_wait.unwrap_or(quirks.display_wait);
+    quirks.clipping = cli.quirk_clipping.unwrap_or(quirks.clipping);
+    quirks.shifting = cli.quirk_shifting.unwrap_or(quirks.shifting);
+    quirks.jumping = cli.quirk_jumping.unwrap_or(quirks.jumping);
+    quirks.key_press = cli.quirk_key_press.unwrap_or(quirks.key_press);
+
+    if cli.print_config {
+        print_config(&cli, &config, &quirks, &program);
+        return;
+    }
+
+    let mut chip8 =
+        Chip8::load(&program, cli.load_address, cli.platform, quirks).unwrap_or_else(|error| {
+            eprintln!("Error: {}", error);
+            process::exit(1);
+        });
+
+    chip8.set_rng(Box::new(SplitMix64::new(seed)));
+
+    let movie = playback.or_else(|| {
+        cli.record.as_ref().map(|_| {
+            Movie::record(MovieOptions {
+                rom: Some(rom_id(&program)),
+                platform: cli.platform,
+                load_address: cli.load_address,
+                quirks,
+                seed,
+                ips: ips(&cli),
+                timing: cli.timing,
+            })
+        })
+    });
+
+    if cli.headless {
+        process::exit(headless(&cli, chip8, movie));
+    }
 
     println!("{:?}", cli);
 
-    let program = std::fs::read(&cli.program).expect("could not read file");
+    let keymap = keymap(&cli, &config, &program);
+    run(&cli, chip8, movie, keymap);
+}
 
-    let quirks = Quirks {
-        vf_reset: cli.quirk_vf_reset,
-        memory: cli.quirk_memory,
-        display_wait: cli.quirk_display_wait,
-        clipping: cli.quirk_clipping,
-        shifting: cli.quirk_shifting,
-        jumping: cli.quirk_jumping,
+/// Run the Chip8 headless as given by the command line and return the exit code
+fn headless(cli: &Cli, chip8: Chip8, movie: Option<Movie>) -> i32 {
+    let mut input = match &cli.input {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|error| error.to_string())
+            .and_then(|script| script.parse().map_err(|error| format!("{}", error)))
+            .unwrap_or_else(|error| {
+                eprintln!(
+                    "Error: could not read input script {}: {}",
+                    path.display(),
+                    error
+                );
+                process::exit(1);
+            }),
+        None => InputScript::default(),
     };
 
-    let chip8 = Chip8::new(program, quirks);
+    let mut machine = Machine::new(chip8, cli.fps, ips(cli));
+    machine.timing = cli.timing;
+    machine.tracer = tracer(cli);
+    machine.profiler = cli
+        .profile
+        .as_ref()
+        .map(|_| Profiler::new(cli.load_address, cli.timing));
+    machine.movie = movie;
+    let outcome = run_headless(&mut machine, cli.frames, &mut input);
+    write_profile(cli, &machine);
+    let matched = finish_movie(cli, &machine);
+
+    match &cli.output {
+        Some(path) => {
+            std::fs::write(path, display_to_pbm(&machine.chip8)).unwrap_or_else(|error| {
+                eprintln!("Error: could not write {}: {}", path.display(), error);
+                process::exit(1);
+            })
+        }
+        None => print!("{}", display_to_ascii(&machine.chip8)),
+    }
+
+    match outcome {
+        HeadlessOutcome::Completed => eprintln!("Completed after {} frames", machine.frames),
+        HeadlessOutcome::Error(error) => eprintln!("Error: {}", error),
+        HeadlessOutcome::TimedOut => eprintln!("Timed out after {} frames", machine.frames),
+    }
+
+    // Playback runs for the frames of the movie, so reaching its end is success. A divergence is an error
+    match (&machine.movie, matched, outcome) {
+        (_, false, _) => 1,
+        (Some(movie), true, HeadlessOutcome::TimedOut)
+            if movie.mode == MovieMode::Play && machine.frames == movie.frames =>
+        {
+            0
+        }
+        _ => outcome.exit_code(),
+    }
+}
+
+/// The tracer as given by the command line, if any
+fn tracer(cli: &Cli) -> Option<Tracer> {
+    let path = cli.trace.as_ref()?;
+    let file = File::create(path).unwrap_or_else(|error| {
+        eprintln!("Error: could not create {}: {}", path.display(), error);
+        process::exit(1);
+    });
+
+    let mut tracer = Tracer::new(Box::new(BufWriter::new(file)), cli.trace_format);
+    tracer.addresses = cli.trace_addresses.clone();
+    tracer.frames = cli.trace_frames.clone();
+
+    Some(tracer)
+}
+
+/// Read a movie for playback or exit with an error
+fn read_movie(path: &PathBuf) -> Movie {
+    std::fs::read_to_string(path)
+        .map_err(|error| error.to_string())
+        .and_then(|movie| movie.parse().map_err(|error| format!("{}", error)))
+        .unwrap_or_else(|error| {
+            eprintln!("Error: could not read movie {}: {}", path.display(), error);
+            process::exit(1);
+        })
+}
 
+/// Write the recorded movie or report the playback. Returns false if the playback diverged or stopped early
+fn finish_movie(cli: &Cli, machine: &Machine) -> bool {
+    let Some(movie) = &machine.movie else {
+        return true;
+    };
+
+    match (movie.mode, &cli.record) {
+        (MovieMode::Record, Some(path)) => {
+            std::fs::write(path, movie.to_string()).unwrap_or_else(|error| {
+                eprintln!("Error: could not write {}: {}", path.display(), error);
+                process::exit(1);
+            });
+            true
+        }
+        _ => {
+            println!("{}", movie.report());
+            movie.matched()
+        }
+    }
+}
+
+/// Write the profile report of the machine to the file given on the command line, if any
+fn write_profile(cli: &Cli, machine: &Machine) {
+    if let (Some(path), Some(profiler)) = (&cli.profile, &machine.profiler) {
+        let report = profiler.report(&machine.chip8, cli.profile_top);
+        std::fs::write(path, report).unwrap_or_else(|error| {
+            eprintln!("Error: could not write {}: {}", path.display(), error);
+            process::exit(1);
+        });
+    }
+}
+
+/// Read a file or exit with an error
+fn read(path: &PathBuf) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|error| {
+        eprintln!("Error: could not read {}: {}", path.display(), error);
+        process::exit(1);
+    })
+}
+
+/// The config file in the XDG config directory, or the default configuration if there is none
+fn config() -> Config {
+    match config_path() {
+        Some(path) => Config::load(&path).unwrap_or_else(|error| {
+            eprintln!("Error: could not read config {}: {}", path.display(), error);
+            process::exit(1);
+        }),
+        None => Config::default(),
+    }
+}
+
+/// Take the settings of the config file for options not given on the command line
+fn configure(cli: &mut Cli, matches: &ArgMatches, settings: &Settings) {
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let s = settings;
+    let q = &s.quirks;
+
+    if let (Some(platform), false) = (s.platform, given("platform")) {
+        cli.platform = platform;
+    }
+    if let (Some(load_address), false) = (s.load_address, given("load_address")) {
+        cli.load_address = load_address;
+    }
+    if let (Some(fps), false) = (s.fps, given("fps")) {
+        cli.fps = fps;
+    }
+    // The speed on the command line replaces both mul and ips
+    if !given("mul") && !given("ips") {
+        cli.mul = s.mul.or(cli.mul);
+        cli.ips = s.ips.or(cli.ips);
+    }
+    if let (Some(speed), false) = (s.speed, given("speed")) {
+        cli.speed = speed;
+    }
+    if let (Some(timing), false) = (s.timing, given("timing")) {
+        cli.timing = timing;
+    }
+    if let (Some(scale), false) = (s.scale, given("scale")) {
+        cli.scale = scale;
+    }
+    if let (Some(color), false) = (s.color, given("color")) {
+        cli.color = color;
+    }
+    if let (Some(background), false) = (s.background, given("background")) {
+        cli.background = background;
+    }
+    if let (Some(color), false) = (s.plane2_color, given("plane2_color")) {
+        cli.plane2_color = color;
+    }
+    if let (Some(color), false) = (s.overlap_color, given("overlap_color")) {
+        cli.overlap_color = color;
+    }
+    if let (Some(pitch), false) = (s.pitch, given("pitch")) {
+        cli.pitch = pitch;
+    }
+    if let (Some(frames), false) = (s.rewind_frames, given("rewind_frames")) {
+        cli.rewind_frames = frames;
+    }
+
+    cli.quirk_vf_reset = cli.quirk_vf_reset.or(q.vf_reset);
+    cli.quirk_memory = cli.quirk_memory.or(q.memory);
+    cli.quirk_display_wait = cli.quirk_display_wait.or(q.display_wait);
+    cli.quirk_clipping = cli.quirk_clipping.or(q.clipping);
+    cli.quirk_shifting = cli.quirk_shifting.or(q.shifting);
+    cli.quirk_jumping = cli.quirk_jumping.or(q.jumping);
+    cli.quirk_key_press = cli.quirk_key_press.or(q.key_press);
+}
+
+/// Print the options and quirks in effect in the format of the config file
+fn print_config(cli: &Cli, config: &Config, quirks: &Quirks, program: &[u8]) {
+    // A missing config file is read as the defaults
+    match config_path() {
+        Some(path) if path.is_file() => println!("# Config file {}", path.display()),
+        Some(path) => println!(
+            "# Config file {} (not found, using defaults)",
+            path.display()
+        ),
+        None => println!("# No config file"),
+    }
+    println!("# ROM {}", rom_id(program));
+    println!("platform = \"{}\"", cli.platform);
+    println!("load-address = 0x{:03X}", cli.load_address);
+    println!("fps = {}", cli.fps);
+    println!("ips = {}", ips(cli));
+    println!("speed = \"{}\"", cli.speed);
+    println!("timing = \"{}\"", cli.timing);
+    println!("scale = {}", cli.scale);
+    println!("color = 0x{:08x}", cli.color);
+    println!("background = 0x{:08x}", cli.background);
+    println!("plane2-color = 0x{:08x}", cli.plane2_color);
+    println!("overlap-color = 0x{:08x}", cli.overlap_color);
+    println!("pitch = {}", cli.pitch);
+    println!("rewind-frames = {}", cli.rewind_frames);
+    println!(
+        "keymap = \"{}\"",
+        config.keymap_name(cli.keymap.as_deref(), program)
+    );
+    println!();
+    println!("[quirks]");
+    println!("vf-reset = {}", quirks.vf_reset);
+    println!("memory = {}", quirks.memory);
+    println!("display-wait = {}", quirks.display_wait);
+    println!("clipping = {}", quirks.clipping);
+    println!("shifting = {}", quirks.shifting);
+    println!("jumping = {}", quirks.jumping);
+    println!("key-press = {}", quirks.key_press);
+}
+
+/// The keymap for the program from the config file and the command line
+fn keymap(cli: &Cli, config: &Config, program: &[u8]) -> Keymap {
+    config
+        .keymap(cli.keymap.as_deref(), program)
+        .unwrap_or_else(|error| {
+            eprintln!("Error: {}", error);
+            process::exit(1);
+        })
+}
+
+/// Instructions per second given on the command line, or mul (default for the platform) times the frames per second
+fn ips(cli: &Cli) -> u32 {
+    cli.ips.unwrap_or(
+        cli.mul.unwrap_or(cli.platform.instructions_per_frame()) as u32 * FRAMES_PER_SECOND,
+    )
+}
+
+/// The debugger as given by the command line. Debugger mode is enabled by any breakpoint or watchpoint
+#[cfg(feature = "sdl")]
+fn debugger(cli: &Cli) -> Debugger {
+    let mut debugger = Debugger::default();
+    debugger.enabled = cli.debug || !cli.breakpoints.is_empty() || !cli.watchpoints.is_empty();
+    debugger.breakpoints = cli.breakpoints.iter().copied().collect();
+    debugger.watchpoints = cli.watchpoints.clone();
+
+    if cli.debug {
+        debugger.pause(Stop::Paused);
+    }
+
+    debugger
+}
+
+/// Run the Chip8 using SDL2 as given by the command line
+#[cfg(feature = "sdl")]
+fn run(cli: &Cli, chip8: Chip8, movie: Option<Movie>, keymap: Keymap) {
     let options: Options = Options {
         fps: cli.fps,
-        mul: cli.mul,
+        ips: ips(cli),
+        speed: cli.speed,
+        timing: cli.timing,
         scale: cli.scale,
         color: cli.color,
         background: cli.background,
+        plane2_color: cli.plane2_color,
+        overlap_color: cli.overlap_color,
         pitch: cli.pitch,
+        keymap,
+        debugger: debugger(cli),
+        gdb: cli.gdb,
+        tracer: tracer(cli),
+        profiler: cli
+            .profile
+            .as_ref()
+            .map(|_| Profiler::new(cli.load_address, cli.timing)),
+        program: cli.program.clone(),
+        rewind_frames: cli.rewind_frames,
+        movie,
     };
 
     let mut emusdl = EmuSdl2::new(chip8, options);
 
     emusdl.run();
+    write_profile(cli, emusdl.machine());
+    finish_movie(cli, emusdl.machine());
+}
+
+/// Without SDL2 only headless runs are possible
+#[cfg(not(feature = "sdl"))]
+fn run(_cli: &Cli, _chip8: Chip8, _movie: Option<Movie>, _keymap: Keymap) {
+    eprintln!("Error: built without SDL2 support (feature sdl), use --headless");
+    process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: [u8; 2] = [0x12, 0x00];
+
+    /// The command line with the settings of the config file for PROGRAM
+    fn configured(args: &[&str], config: &str) -> Cli {
+        let config: Config = config.parse().unwrap();
+        let matches = Cli::command()
+            .try_get_matches_from(["chip8rs"].iter().chain(args).chain(&["rom.ch8"]))
+            .unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        configure(&mut cli, &matches, &config.settings(&PROGRAM));
+        cli
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let config = format!(
+            "fps = 30\nscale = 8\nmul = 20\n[quirks]\nshifting = true\njumping = true\n\n[roms.{}]\nscale = 12\nspeed = \"2x\"",
+            rom_id(&PROGRAM)
+        );
+
+        // Global, ROM and default values
+        let cli = configured(&[], &config);
+        assert_eq!(cli.fps, 30);
+        assert_eq!(cli.scale, 12);
+        assert_eq!(cli.speed, Speed::Double);
+        assert_eq!(cli.pitch, 432);
+        assert_eq!(ips(&cli), 20 * FRAMES_PER_SECOND);
+        assert_eq!(cli.quirk_shifting, Some(true));
+
+        // The command line wins over both, even when given its default value
+        let cli = configured(
+            &[
+                "--fps",
+                "60",
+                "--scale",
+                "4",
+                "--speed",
+                "1x",
+                "--ips",
+                "700",
+                "--quirk-shifting=false",
+                "--quirk-jumping",
+            ],
+            &config,
+        );
+        assert_eq!(cli.fps, 60);
+        assert_eq!(cli.scale, 4);
+        assert_eq!(cli.speed, Speed::Normal);
+        assert_eq!(ips(&cli), 700);
+        assert_eq!(cli.quirk_shifting, Some(false));
+        assert_eq!(cli.quirk_jumping, Some(true));
+    }
 }
@@ -0,0 +1,462 @@
+//! Recording of keyboard input with the options in use, and deterministic playback that checks the display against the recording
+use std::{fmt, str::FromStr};
+
+use crate::{
+    chip8::{Chip8, Platform, Quirks, KEYBOARD_SIZE, PROGRAM_START},
+    headless::{ScriptError, ScriptEvent},
+    machine::FRAMES_PER_SECOND,
+    timing::Timing,
+};
+
+/// First line of a movie file
+const MOVIE_HEADER: &str = "# chip8rs movie";
+/// Version of the movie format. Version 2 replaced the instructions per frame (mul) with instructions per second (ips)
+const MOVIE_VERSION: u32 = 2;
+/// Oldest version of the movie format that can be played back
+const MOVIE_MIN_VERSION: u32 = 1;
+/// Names of the quirks in the order of [quirk_values]
+const QUIRK_NAMES: [&str; 7] = [
+    "vf-reset",
+    "memory",
+    "display-wait",
+    "clipping",
+    "shifting",
+    "jumping",
+    "key-press",
+];
+
+/// Record or play back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieMode {
+    /// Record the keyboard and display
+    Record,
+    /// Replace the keyboard with the recording and compare the display
+    Play,
+}
+
+/// The options a movie was recorded with. They must be the same on playback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovieOptions {
+    /// SHA-1 of the program in hex. Movies of version 1 don't have it
+    pub rom: Option<String>,
+    pub platform: Platform,
+    pub load_address: usize,
+    pub quirks: Quirks,
+    /// Seed of the random number generator
+    pub seed: u64,
+    /// Instructions per second
+    pub ips: u32,
+    /// Timing of instructions
+    pub timing: Timing,
+}
+
+/// Keyboard changes and display checksums per frame
+#[derive(Debug, Clone)]
+pub struct Movie {
+    pub mode: MovieMode,
+    pub options: MovieOptions,
+    /// Number of frames recorded
+    pub frames: u64,
+    /// Key presses and releases by frame
+    events: Vec<ScriptEvent>,
+    /// Checksums of the display by frame, for frames where it was updated
+    displays: Vec<(u64, u64)>,
+    /// Index of the next event to play back
+    next_event: usize,
+    /// Index of the next display checksum to compare on playback
+    next_display: usize,
+    /// Keyboard state of the previous frame
+    keyboard: [bool; KEYBOARD_SIZE],
+    /// First frame where playback differed from the recording
+    pub divergence: Option<u64>,
+}
+
+impl Movie {
+    /// Start recording with the options in use
+    pub fn record(options: MovieOptions) -> Self {
+        Movie {
+            mode: MovieMode::Record,
+            options,
+            frames: 0,
+            events: Vec::new(),
+            displays: Vec::new(),
+            next_event: 0,
+            next_display: 0,
+            keyboard: [false; KEYBOARD_SIZE],
+            divergence: None,
+        }
+    }
+
+    /// Record the keyboard changes or replace the keyboard with the recorded one at the start of a frame
+    pub fn input(&mut self, frame: u64, keyboard: &mut [bool; KEYBOARD_SIZE]) {
+        match self.mode {
+            MovieMode::Record => {
+                for (key, (pressed, before)) in keyboard.iter().zip(&self.keyboard).enumerate() {
+                    if pressed != before {
+                        self.events.push(ScriptEvent {
+                            frame,
+                            key,
+                            pressed: *pressed,
+                        });
+                    }
+                }
+                self.keyboard = *keyboard;
+                self.frames = frame + 1;
+            }
+            MovieMode::Play => {
+                while let Some(event) = self.events.get(self.next_event) {
+                    if event.frame > frame {
+                        break;
+                    }
+                    self.keyboard[event.key] = event.pressed;
+                    self.next_event += 1;
+                }
+                *keyboard = self.keyboard;
+            }
+        }
+    }
+
+    /// Record or compare the display at the end of a frame where it was updated
+    pub fn display(&mut self, frame: u64, chip8: &Chip8) {
+        let checksum = checksum(chip8);
+
+        match self.mode {
+            MovieMode::Record => self.displays.push((frame, checksum)),
+            MovieMode::Play => {
+                if self.divergence.is_some() || frame >= self.frames {
+                    return;
+                }
+
+                // Any recorded update skipped or not matching this one is a divergence
+                let expected = self.displays.get(self.next_display).copied();
+                self.next_display += 1;
+                if expected != Some((frame, checksum)) {
+                    let at = expected.map_or(frame, |(expected, _)| expected.min(frame));
+                    eprintln!("Playback diverged from the recording at frame {}", at);
+                    self.divergence = Some(at);
+                }
+            }
+        }
+    }
+
+    /// The playback matched the recording: no display differed and every recorded display update happened
+    pub fn matched(&self) -> bool {
+        self.divergence.is_none() && self.next_display >= self.displays.len()
+    }
+
+    /// Summary of a playback
+    pub fn report(&self) -> String {
+        match self.divergence {
+            Some(frame) => format!("Playback diverged from the recording at frame {}", frame),
+            None if self.next_display < self.displays.len() => format!(
+                "Playback stopped before the end of the recording ({} of {} display updates)",
+                self.next_display,
+                self.displays.len()
+            ),
+            None => format!("Playback matched the recording for {} frames", self.frames),
+        }
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let o = &self.options;
+        let quirks: Vec<&str> = QUIRK_NAMES
+            .iter()
+            .zip(quirk_values(&o.quirks))
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect();
+
+        writeln!(f, "{}", MOVIE_HEADER)?;
+        writeln!(f, "version {}", MOVIE_VERSION)?;
+        if let Some(rom) = &o.rom {
+            writeln!(f, "rom {}", rom)?;
+        }
+        writeln!(f, "platform {}", o.platform)?;
+        writeln!(f, "load-address 0x{:03X}", o.load_address)?;
+        writeln!(f, "quirks {}", quirks.join(" "))?;
+        writeln!(f, "seed {}", o.seed)?;
+        writeln!(f, "ips {}", o.ips)?;
+        writeln!(f, "timing {}", o.timing)?;
+        writeln!(f, "frames {}", self.frames)?;
+        for event in &self.events {
+            let action = if event.pressed { "down" } else { "up" };
+            writeln!(f, "{} {:X} {}", event.frame, event.key, action)?;
+        }
+        for (frame, checksum) in &self.displays {
+            writeln!(f, "display {} {:016x}", frame, checksum)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Movie {
+    type Err = ScriptError;
+
+    /// Parse a movie for playback
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut movie = Movie::record(MovieOptions {
+            rom: None,
+            platform: Platform::default(),
+            load_address: PROGRAM_START,
+            quirks: Platform::default().quirks(),
+            seed: 0,
+            ips: 0,
+            timing: Timing::default(),
+        });
+        movie.mode = MovieMode::Play;
+        let mut quirks = None;
+        let mut load_address = None;
+        let mut version = MOVIE_VERSION;
+
+        for (index, line) in s.lines().enumerate() {
+            let error = |message: &str| ScriptError {
+                line: index + 1,
+                message: message.to_string(),
+            };
+            let line = line.trim();
+
+            if index == 0 && line != MOVIE_HEADER {
+                return Err(error("not a movie file"));
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                ["version", number] => {
+                    version = number
+                        .parse()
+                        .ok()
+                        .filter(|number| (MOVIE_MIN_VERSION..=MOVIE_VERSION).contains(number))
+                        .ok_or_else(|| error("unsupported version"))?;
+                }
+                ["rom", rom] => movie.options.rom = Some(rom.to_ascii_lowercase()),
+                ["platform", platform] => {
+                    movie.options.platform = platform.parse().map_err(|e: String| error(&e))?
+                }
+                ["load-address", address] => {
+                    let address = address.trim_start_matches("0x");
+                    load_address = Some(
+                        usize::from_str_radix(address, 16)
+                            .map_err(|_| error("invalid load address"))?,
+                    );
+                }
+                ["quirks", ref names @ ..] => {
+                    let mut values = [false; 7];
+                    for name in names {
+                        let index = QUIRK_NAMES
+                            .iter()
+                            .position(|quirk| quirk == name)
+                            .ok_or_else(|| error("unknown quirk"))?;
+                        values[index] = true;
+                    }
+                    let [vf_reset, memory, display_wait, clipping, shifting, jumping, key_press] =
+                        values;
+                    quirks = Some(Quirks {
+                        vf_reset,
+                        memory,
+                        display_wait,
+                        clipping,
+                        shifting,
+                        jumping,
+                        key_press,
+                    });
+                }
+                ["seed", seed] => {
+                    movie.options.seed = seed.parse().map_err(|_| error("invalid seed"))?
+                }
+                ["ips", ips] => {
+                    movie.options.ips = ips.parse().map_err(|_| error("invalid ips"))?
+                }
+                // Instructions per frame in version 1
+                ["mul", mul] if version == 1 => {
+                    movie.options.ips = mul
+                        .parse::<u32>()
+                        .ok()
+                        .and_then(|mul| mul.checked_mul(FRAMES_PER_SECOND))
+                        .ok_or_else(|| error("invalid mul"))?
+                }
+                ["timing", timing] => {
+                    movie.options.timing = timing.parse().map_err(|e: String| error(&e))?
+                }
+                ["frames", frames] => {
+                    movie.frames = frames.parse().map_err(|_| error("invalid frames"))?
+                }
+                ["display", frame, checksum] => movie.displays.push((
+                    frame.parse().map_err(|_| error("invalid frame number"))?,
+                    u64::from_str_radix(checksum, 16).map_err(|_| error("invalid checksum"))?,
+                )),
+                // Key events use the input script syntax
+                [_, _, _] => movie
+                    .events
+                    .push(line.parse().map_err(|message: String| error(&message))?),
+                _ => return Err(error("unknown line")),
+            }
+        }
+
+        // Defaults for anything missing
+        movie.options.quirks = quirks.unwrap_or(movie.options.platform.quirks());
+        movie.options.load_address = load_address.unwrap_or(PROGRAM_START);
+        if movie.options.ips == 0 {
+            movie.options.ips =
+                movie.options.platform.instructions_per_frame() as u32 * FRAMES_PER_SECOND;
+        }
+        movie.events.sort_by_key(|event| event.frame);
+
+        Ok(movie)
+    }
+}
+
+/// The quirks as values in the order of [QUIRK_NAMES]
+fn quirk_values(quirks: &Quirks) -> [bool; 7] {
+    [
+        quirks.vf_reset,
+        quirks.memory,
+        quirks.display_wait,
+        quirks.clipping,
+        quirks.shifting,
+        quirks.jumping,
+        quirks.key_press,
+    ]
+}
+
+/// FNV-1a checksum of the visible display
+fn checksum(chip8: &Chip8) -> u64 {
+    let (width, height) = (chip8.display_width(), chip8.display_height());
+
+    chip8
+        .display
+        .iter()
+        .take(height)
+        .flat_map(|row| row.iter().take(width))
+        .fold(0xcbf29ce484222325, |hash, pixel| {
+            (hash ^ *pixel as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        headless::{run_headless, InputScript},
+        machine::Machine,
+    };
+
+    /// Waits for a key and draws its digit next to the last one
+    const PROGRAM: [u8; 10] = [0xF0, 0x0A, 0xF0, 0x29, 0xD1, 0x15, 0x71, 0x05, 0x12, 0x00];
+    /// Keys 3 and 5 pressed and released
+    const SCRIPT: &str = "2 3 down\n4 3 up\n8 5 down\n9 5 up";
+
+    fn options() -> MovieOptions {
+        let platform = Platform::SchipModern;
+        MovieOptions {
+            rom: Some("0123456789abcdef0123456789abcdef01234567".to_string()),
+            platform,
+            load_address: PROGRAM_START,
+            quirks: platform.quirks(),
+            seed: 7,
+            ips: 600,
+            timing: Timing::Fixed,
+        }
+    }
+
+    /// Run program for frames with the movie and input script and return the movie
+    fn run(program: &[u8], movie: Movie, script: &str, frames: u64) -> Movie {
+        let o = &movie.options;
+        let chip8 = Chip8::load(program, o.load_address, o.platform, o.quirks).unwrap();
+        let mut machine = Machine::new(chip8, 60, o.ips);
+        machine.movie = Some(movie);
+        run_headless(
+            &mut machine,
+            frames,
+            &mut script.parse::<InputScript>().unwrap(),
+        );
+        machine.movie.unwrap()
+    }
+
+    /// A recording of the program for 20 frames
+    fn recording() -> Movie {
+        run(&PROGRAM, Movie::record(options()), SCRIPT, 20)
+    }
+
+    #[test]
+    fn recording_round_trip() {
+        let recorded = recording();
+        assert_eq!(recorded.frames, 20);
+        assert_eq!(recorded.events.len(), 4);
+        assert_eq!(recorded.displays.len(), 2);
+
+        let movie: Movie = recorded.to_string().parse().unwrap();
+        assert_eq!(movie.mode, MovieMode::Play);
+        assert_eq!(movie.options, recorded.options);
+        assert_eq!(movie.frames, recorded.frames);
+        assert_eq!(movie.events, recorded.events);
+        assert_eq!(movie.displays, recorded.displays);
+        assert_eq!(movie.to_string(), recorded.to_string());
+    }
+
+    #[test]
+    fn playback_matches() {
+        let movie = recording().to_string().parse().unwrap();
+        let played = run(&PROGRAM, movie, "", 20);
+
+        assert!(played.matched());
+        assert_eq!(
+            played.report(),
+            "Playback matched the recording for 20 frames"
+        );
+    }
+
+    #[test]
+    fn playback_finds_first_divergence() {
+        let recorded = recording();
+        let movie = recorded.to_string().parse().unwrap();
+        // Draws 4 of the 5 rows of the digits, so the first display differs
+        let mut program = PROGRAM;
+        program[5] = 0x14;
+        let played = run(&program, movie, "", 20);
+
+        assert_eq!(played.divergence, Some(recorded.displays[0].0));
+        assert!(!played.matched());
+    }
+
+    #[test]
+    fn playback_stopping_early_does_not_match() {
+        let recorded = recording();
+        let movie = recorded.to_string().parse().unwrap();
+        let played = run(&PROGRAM, movie, "", recorded.displays[1].0);
+
+        assert_eq!(played.divergence, None);
+        assert!(!played.matched());
+        assert_eq!(
+            played.report(),
+            "Playback stopped before the end of the recording (1 of 2 display updates)"
+        );
+    }
+
+    #[test]
+    fn version_1_uses_mul() {
+        let movie: Movie = "# chip8rs movie\nversion 1\nplatform vip\nmul 15\nframes 3"
+            .parse()
+            .unwrap();
+        assert_eq!(movie.options.ips, 900);
+        assert_eq!(movie.options.rom, None);
+
+        let error = "# chip8rs movie\nversion 1\nmul 4294967295"
+            .parse::<Movie>()
+            .unwrap_err();
+        assert_eq!((error.line, error.message.as_str()), (3, "invalid mul"));
+
+        let error = "# chip8rs movie\nversion 2\nmul 15"
+            .parse::<Movie>()
+            .unwrap_err();
+        assert_eq!((error.line, error.message.as_str()), (3, "unknown line"));
+
+        let error = "# chip8rs movie\nversion 3".parse::<Movie>().unwrap_err();
+        assert_eq!(error.message, "unsupported version");
+    }
+}
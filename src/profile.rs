@@ -0,0 +1,271 @@
+//! Profiling of executed instructions per address, per instruction and per subroutine. With VIP timing instructions are weighted by their machine cycles
+use std::{cmp::Reverse, collections::BTreeMap, fmt::Write};
+
+use crate::{
+    chip8::{Chip8, Instruction},
+    disasm::{format_at, Syntax},
+    timing::{vip_cycles, Timing},
+};
+
+/// A subroutine in the call tree
+#[derive(Debug, Clone, Default)]
+struct Node {
+    /// Address of the subroutine
+    address: usize,
+    /// Index of the calling subroutine. None for the program itself
+    parent: Option<usize>,
+    /// Index of called subroutines by address
+    children: BTreeMap<usize, usize>,
+    /// Number of calls from the parent
+    calls: u64,
+    /// Cost of the instructions executed in the subroutine itself
+    cycles: u64,
+}
+
+/// Costs of executed instructions collected while running. An instruction costs 1, or its machine cycles with VIP timing
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    /// Timing the instructions are weighted by
+    timing: Timing,
+    /// Cost per address
+    addresses: BTreeMap<usize, u64>,
+    /// Cost per instruction
+    instructions: BTreeMap<String, u64>,
+    /// Cost per frame
+    frames: Vec<u64>,
+    /// Call tree with the program at index 0
+    nodes: Vec<Node>,
+    /// Index of the subroutine being executed
+    current: usize,
+}
+
+impl Profiler {
+    /// Create a profiler for a program starting at an address, run with a timing
+    pub fn new(start: usize, timing: Timing) -> Self {
+        Profiler {
+            timing,
+            addresses: BTreeMap::new(),
+            instructions: BTreeMap::new(),
+            frames: Vec::new(),
+            nodes: vec![Node {
+                address: start,
+                calls: 1,
+                ..Node::default()
+            }],
+            current: 0,
+        }
+    }
+
+    /// Count the cost of the instruction about to be executed by the Chip8 in a frame
+    pub fn record(&mut self, chip8: &Chip8, frame: u64) {
+        let Ok(opcode) = chip8.fetch() else {
+            return;
+        };
+        let instr = Chip8::decode(opcode, chip8.platform);
+        let cost = match self.timing {
+            Timing::Fixed => 1,
+            Timing::Vip => vip_cycles(chip8, instr) as u64,
+        };
+
+        *self.addresses.entry(chip8.pc()).or_default() += cost;
+        *self.instructions.entry(variant(instr)).or_default() += cost;
+
+        let frame = frame as usize;
+        if self.frames.len() <= frame {
+            self.frames.resize(frame + 1, 0);
+        }
+        self.frames[frame] += cost;
+
+        self.nodes[self.current].cycles += cost;
+
+        // Calls and returns move within the call tree. The instruction itself counts to the caller and callee respectively
+        match instr {
+            Instruction::Call(address) => {
+                let index = match self.nodes[self.current].children.get(&address) {
+                    Some(index) => *index,
+                    None => {
+                        let index = self.nodes.len();
+                        self.nodes.push(Node {
+                            address,
+                            parent: Some(self.current),
+                            ..Node::default()
+                        });
+                        self.nodes[self.current].children.insert(address, index);
+                        index
+                    }
+                };
+                self.nodes[index].calls += 1;
+                self.current = index;
+            }
+            Instruction::Ret => {
+                if let Some(parent) = self.nodes[self.current].parent {
+                    self.current = parent;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Cost of a subroutine including called subroutines
+    fn total(&self, index: usize) -> u64 {
+        let node = &self.nodes[index];
+        node.cycles
+            + node
+                .children
+                .values()
+                .map(|child| self.total(*child))
+                .sum::<u64>()
+    }
+
+    /// What the costs count
+    fn unit(&self) -> &'static str {
+        match self.timing {
+            Timing::Fixed => "instructions",
+            Timing::Vip => "machine cycles",
+        }
+    }
+
+    /// A report with the top addresses, instructions, call tree and cost per frame. The Chip8 is used to show the instruction at each address
+    pub fn report(&self, chip8: &Chip8, top: usize) -> String {
+        let total = self.total(0);
+        let unit = self.unit();
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+        let memory = &chip8.memory()[..chip8.platform.memory_size()];
+        let mut report = String::new();
+
+        let _ = writeln!(
+            report,
+            "Executed {} {} in {} frames",
+            total,
+            unit,
+            self.frames.len()
+        );
+
+        let _ = writeln!(report, "\nTop {} addresses ({})", top, unit);
+        let mut addresses: Vec<(&usize, &u64)> = self.addresses.iter().collect();
+        addresses.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (address, count) in addresses.into_iter().take(top) {
+            let _ = writeln!(
+                report,
+                "  {:04X} {:>12} {:>6.2}%  {}",
+                address,
+                count,
+                percent(*count),
+                format_at(memory, *address, chip8.platform, Syntax::Chip8rs).unwrap_or_default()
+            );
+        }
+
+        let _ = writeln!(report, "\nPer instruction ({})", unit);
+        let mut instructions: Vec<(&String, &u64)> = self.instructions.iter().collect();
+        instructions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (name, count) in instructions {
+            let _ = writeln!(
+                report,
+                "  {:<6} {:>12} {:>6.2}%",
+                name,
+                count,
+                percent(*count)
+            );
+        }
+
+        let _ = writeln!(
+            report,
+            "\nCall tree (address, calls, total and self {})",
+            unit
+        );
+        self.write_node(&mut report, 0, 1, &percent);
+
+        let _ = writeln!(report, "\nPer frame ({})", unit);
+        for (frame, count) in self.frames.iter().enumerate() {
+            let _ = writeln!(report, "  {:>8} {:>8}", frame, count);
+        }
+
+        report
+    }
+
+    /// Write a subroutine and its children indented by depth, most expensive first
+    fn write_node(
+        &self,
+        report: &mut String,
+        index: usize,
+        depth: usize,
+        percent: &dyn Fn(u64) -> f64,
+    ) {
+        let node = &self.nodes[index];
+        let total = self.total(index);
+        let _ = writeln!(
+            report,
+            "{:indent$}{:04X} {:>8} {:>12} {:>6.2}% {:>12} {:>6.2}%",
+            "",
+            node.address,
+            node.calls,
+            total,
+            percent(total),
+            node.cycles,
+            percent(node.cycles),
+            indent = depth * 2
+        );
+
+        let mut children: Vec<(u64, usize)> = node
+            .children
+            .values()
+            .map(|child| (self.total(*child), *child))
+            .collect();
+        children.sort_by_key(|(total, _)| Reverse(*total));
+        for (_, child) in children {
+            self.write_node(report, child, depth + 1, percent);
+        }
+    }
+}
+
+/// Name of the instruction variant, e.g. Draw for Draw(0, 1, 5)
+fn variant(instr: Instruction) -> String {
+    let name = format!("{:?}", instr);
+    match name.split_once('(') {
+        Some((variant, _)) => variant.to_string(),
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Platform;
+
+    /// Profile of LDB V0 1 and CLS run once with a timing
+    fn profile(timing: Timing) -> Profiler {
+        let platform = Platform::CosmacVip;
+        let program = [0x60, 0x01, 0x00, 0xE0];
+        let mut chip8 = Chip8::load(&program, 0x200, platform, platform.quirks()).unwrap();
+        let mut profiler = Profiler::new(0x200, timing);
+        for _ in 0..2 {
+            profiler.record(&chip8, 0);
+            chip8.step().unwrap();
+        }
+        profiler
+    }
+
+    #[test]
+    fn fixed_timing_counts_instructions() {
+        let profiler = profile(Timing::Fixed);
+
+        assert_eq!(profiler.addresses[&0x200], 1);
+        assert_eq!(profiler.addresses[&0x202], 1);
+        assert_eq!(profiler.frames, [2]);
+    }
+
+    #[test]
+    fn vip_timing_counts_machine_cycles() {
+        let profiler = profile(Timing::Vip);
+
+        assert_eq!(profiler.addresses[&0x200], 40 + 6);
+        assert_eq!(profiler.addresses[&0x202], 40 + 3078);
+        assert_eq!(profiler.instructions["Cls"], 40 + 3078);
+        assert_eq!(profiler.total(0), 40 + 6 + 40 + 3078);
+
+        let platform = Platform::CosmacVip;
+        let chip8 = Chip8::load(&[0x00, 0xE0], 0x200, platform, platform.quirks()).unwrap();
+        let report = profiler.report(&chip8, 5);
+        assert!(report.starts_with("Executed 3164 machine cycles in 1 frames"));
+    }
+}
@@ -0,0 +1,51 @@
+//! Random numbers for the RND instruction, seedable for reproducible runs
+/// A source of random numbers with a state that can be saved and restored
+pub trait RandomSource {
+    /// The next random byte
+    fn next_u8(&mut self) -> u8;
+    /// The internal state
+    fn state(&self) -> u64;
+    /// Restore the internal state
+    fn set_state(&mut self, state: u64);
+}
+
+/// The SplitMix64 generator. Fast, small and good enough for games
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    /// Create a generator from a seed. The same seed gives the same sequence
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    /// Create a generator with a random seed
+    pub fn from_entropy() -> Self {
+        SplitMix64::new(rand::random())
+    }
+
+    /// The next random 64 bit number
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for SplitMix64 {
+    fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.state = state;
+    }
+}
@@ -0,0 +1,80 @@
+//! Rewinding a Chip8 frame by frame through a bounded history of snapshots
+use std::collections::VecDeque;
+
+use crate::chip8::Chip8;
+
+/// Changed bytes of a snapshot as (offset, previous value)
+type Delta = Vec<(u32, u8)>;
+
+/// A ring buffer of per-frame snapshots. Only the latest snapshot is kept in full, older ones are stored as the bytes that differ from the next one
+#[derive(Debug, Clone)]
+pub struct Rewind {
+    /// Maximum number of frames to go back
+    capacity: usize,
+    /// The latest snapshot as a save state
+    latest: Vec<u8>,
+    /// Deltas turning a snapshot into the one before it, oldest first
+    deltas: VecDeque<Delta>,
+}
+
+impl Rewind {
+    /// Create a history of at most capacity frames
+    pub fn new(capacity: usize) -> Self {
+        Rewind {
+            capacity,
+            latest: Vec::new(),
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Number of frames that can be rewound
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// No frames can be rewound
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Take a snapshot of the Chip8. Called once per frame
+    pub fn capture(&mut self, chip8: &Chip8) {
+        let state = chip8.save_state();
+
+        // The size only changes with the platform, e.g. after loading a save state. Older snapshots are then dropped
+        if state.len() == self.latest.len() {
+            let delta: Delta = state
+                .iter()
+                .zip(&self.latest)
+                .enumerate()
+                .filter(|(_, (new, old))| new != old)
+                .map(|(offset, (_, old))| (offset as u32, *old))
+                .collect();
+            self.deltas.push_back(delta);
+            if self.deltas.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        } else {
+            self.deltas.clear();
+        }
+
+        self.latest = state;
+    }
+
+    /// Restore the Chip8 to the snapshot one frame back. The keyboard is left as is. Returns false if there is no history left
+    pub fn rewind(&mut self, chip8: &mut Chip8) -> bool {
+        let Some(delta) = self.deltas.pop_back() else {
+            return false;
+        };
+
+        for (offset, value) in delta {
+            self.latest[offset as usize] = value;
+        }
+
+        let keyboard = chip8.keyboard;
+        let restored = chip8.load_state(&self.latest).is_ok();
+        chip8.keyboard = keyboard;
+
+        restored
+    }
+}
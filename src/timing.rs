@@ -0,0 +1,184 @@
+//! Timing of instruction execution, either a fixed number of instructions per second or the machine cycles of the COSMAC VIP, and the speed it is run at
+use std::{fmt, str::FromStr};
+
+use crate::chip8::{Chip8, Instruction};
+
+/// Machine cycles of the VIP per frame (1.76064 MHz, 8 clock cycles per machine cycle, 60 frames per second)
+pub const VIP_CYCLES_PER_FRAME: i64 = 3668;
+/// Machine cycles per frame taken by the display interrupt. The CPU is busy feeding the display during the 128 visible lines
+pub const VIP_INTERRUPT_CYCLES: i64 = 1832;
+/// Machine cycles taken by the interpreter to fetch and decode an instruction
+const FETCH_CYCLES: u32 = 40;
+
+/// How the instructions executed in a frame are counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Timing {
+    /// A fixed number of instructions per second
+    #[default]
+    Fixed,
+    /// Instructions cost the machine cycles of the COSMAC VIP interpreter and drawing waits for the vertical blank interrupt
+    Vip,
+}
+
+impl Timing {
+    /// Names of all timings as used on the command line
+    pub const NAMES: [&'static str; 2] = ["fixed", "vip"];
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Timing::NAMES[*self as usize])
+    }
+}
+
+impl FromStr for Timing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Timing::Fixed),
+            "vip" => Ok(Timing::Vip),
+            _ => Err(format!("unknown timing {s}")),
+        }
+    }
+}
+
+/// Speed of emulated time relative to real time. The program behaves the same at any speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Speed {
+    Quarter,
+    Half,
+    #[default]
+    Normal,
+    Double,
+    Quadruple,
+    /// As fast as possible
+    Unlimited,
+}
+
+impl Speed {
+    /// Names of all speeds as used on the command line, slowest first
+    pub const NAMES: [&'static str; 6] = ["0.25x", "0.5x", "1x", "2x", "4x", "unlimited"];
+    /// All speeds, slowest first
+    const ALL: [Speed; 6] = [
+        Speed::Quarter,
+        Speed::Half,
+        Speed::Normal,
+        Speed::Double,
+        Speed::Quadruple,
+        Speed::Unlimited,
+    ];
+
+    /// Multiplier of real time. None if unlimited
+    pub fn factor(&self) -> Option<f64> {
+        match self {
+            Speed::Quarter => Some(0.25),
+            Speed::Half => Some(0.5),
+            Speed::Normal => Some(1.0),
+            Speed::Double => Some(2.0),
+            Speed::Quadruple => Some(4.0),
+            Speed::Unlimited => None,
+        }
+    }
+
+    /// The next faster speed. Unlimited stays unlimited
+    pub fn faster(&self) -> Speed {
+        Speed::ALL[(*self as usize + 1).min(Speed::ALL.len() - 1)]
+    }
+
+    /// The next slower speed. A quarter stays a quarter
+    pub fn slower(&self) -> Speed {
+        Speed::ALL[(*self as usize).saturating_sub(1)]
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Speed::NAMES[*self as usize])
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Speed::NAMES
+            .iter()
+            .position(|name| *name == s)
+            .map(|index| Speed::ALL[index])
+            .ok_or_else(|| format!("unknown speed {s}"))
+    }
+}
+
+/// Machine cycles the VIP interpreter takes for an instruction about to be executed by the Chip8, including fetch and decode.
+/// Skips cost more when taken. Instructions the VIP doesn't have cost as much as a register load
+pub fn vip_cycles(chip8: &Chip8, instr: Instruction) -> u32 {
+    let v = chip8.registers();
+    let key_pressed = |x: usize| chip8.keyboard[v[x] as usize & 0xF];
+    let skip = |taken: bool| if taken { 14 } else { 10 };
+
+    let execute = match instr {
+        Instruction::Cls => 3078,
+        Instruction::Ret => 10,
+        Instruction::Jmp(_) => 12,
+        Instruction::Call(_) => 26,
+        Instruction::Skeb(x, kk) => skip(v[x] == kk),
+        Instruction::Skneb(x, kk) => skip(v[x] != kk),
+        Instruction::Ske(x, y) => skip(v[x] == v[y]) + 4,
+        Instruction::Skne(x, y) => skip(v[x] != v[y]) + 4,
+        Instruction::Ldb(_, _) => 6,
+        Instruction::Addb(_, _) => 10,
+        Instruction::Ld(_, _) => 12,
+        Instruction::Or(_, _)
+        | Instruction::And(_, _)
+        | Instruction::Xor(_, _)
+        | Instruction::Add(_, _)
+        | Instruction::Sub(_, _)
+        | Instruction::Subr(_, _)
+        | Instruction::Shr(_, _)
+        | Instruction::Shl(_, _) => 44,
+        Instruction::Ldi(_) => 12,
+        // One more cycle pair when V0 moves the target to another page
+        Instruction::Jmpz(nnn) => {
+            if (nnn + v[0] as usize) & 0xF00 != nnn & 0xF00 {
+                24
+            } else {
+                22
+            }
+        }
+        Instruction::Rnd(_, _) => 36,
+        Instruction::Draw(x, y, n) => draw_cycles(v[x], v[y], n),
+        Instruction::Skp(x) => skip(key_pressed(x)) + 4,
+        Instruction::Sknp(x) => skip(!key_pressed(x)) + 4,
+        Instruction::Ldft(_) | Instruction::Ldtt(_) | Instruction::Ldst(_) => 10,
+        Instruction::Ldkp(_) => 10,
+        // One more cycle pair when I crosses a page
+        Instruction::Addi(x) => {
+            if (chip8.i() + v[x] as usize) & 0xF00 != chip8.i() & 0xF00 {
+                18
+            } else {
+                16
+            }
+        }
+        Instruction::Font(_) => 16,
+        // Digits are found by repeated subtraction
+        Instruction::Bcd(x) => {
+            let digits = v[x] / 100 + v[x] / 10 % 10 + v[x] % 10;
+            80 + 16 * digits as u32
+        }
+        Instruction::Sreg(x) | Instruction::Lreg(x) => 14 + 14 * (x as u32 + 1),
+        _ => 6,
+    };
+
+    FETCH_CYCLES + execute
+}
+
+/// Machine cycles of drawing a sprite of n rows at VX, VY. Sprites not aligned to a byte are shifted bit by bit and span two bytes per row.
+/// Rows below the display are not drawn
+fn draw_cycles(vx: u8, vy: u8, n: u8) -> u32 {
+    let shift = vx as u32 % 8;
+    let rows = (n as u32).min(32 - vy as u32 % 32);
+    let row = if shift == 0 { 34 } else { 46 + 4 * shift };
+
+    26 + rows * row
+}
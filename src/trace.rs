@@ -0,0 +1,156 @@
+//! Tracing of executed instructions to a file as text or JSON Lines
+use std::{fmt, io, io::Write, ops::RangeInclusive, str::FromStr};
+
+use crate::{
+    chip8::Chip8,
+    debugger::parse_address,
+    disasm::{format_at, Syntax},
+};
+
+/// Format of trace records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    /// One line of columns per instruction
+    #[default]
+    Text,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl TraceFormat {
+    /// Names of all formats as used on the command line
+    pub const NAMES: [&'static str; 2] = ["text", "jsonl"];
+}
+
+impl fmt::Display for TraceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(TraceFormat::NAMES[*self as usize])
+    }
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "jsonl" => Ok(TraceFormat::Jsonl),
+            _ => Err(format!("unknown trace format {s}")),
+        }
+    }
+}
+
+/// Writes a record for every executed instruction within the address and frame filters
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    format: TraceFormat,
+    /// Only trace instructions at these addresses
+    pub addresses: Option<RangeInclusive<usize>>,
+    /// Only trace instructions in these frames
+    pub frames: Option<RangeInclusive<u64>>,
+    /// Number of instructions executed, including those filtered out
+    cycles: u64,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("addresses", &self.addresses)
+            .field("frames", &self.frames)
+            .field("cycles", &self.cycles)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    /// Create a tracer writing records in a format. Nothing is filtered
+    pub fn new(writer: Box<dyn Write>, format: TraceFormat) -> Self {
+        Tracer {
+            writer,
+            format,
+            addresses: None,
+            frames: None,
+            cycles: 0,
+        }
+    }
+
+    /// Record the instruction about to be executed by the Chip8 in a frame, with the state before execution
+    pub fn record(&mut self, chip8: &Chip8, frame: u64) -> io::Result<()> {
+        let cycle = self.cycles;
+        self.cycles += 1;
+
+        let pc = chip8.pc();
+        if self.addresses.as_ref().is_some_and(|a| !a.contains(&pc))
+            || self.frames.as_ref().is_some_and(|f| !f.contains(&frame))
+        {
+            return Ok(());
+        }
+
+        let opcode = chip8.fetch().unwrap_or_default();
+        let memory = &chip8.memory()[..chip8.platform.memory_size()];
+        let mnemonic = format_at(memory, pc, chip8.platform, Syntax::Chip8rs).unwrap_or_default();
+        let registers = chip8.registers();
+
+        match self.format {
+            TraceFormat::Text => {
+                let registers: String = registers.iter().map(|v| format!("{:02X}", v)).collect();
+                writeln!(
+                    self.writer,
+                    "{:>10} {:>8} {:04X} {:04X} {:<20} V:{} I:{:04X} SP:{:X} DT:{:02X} ST:{:02X}",
+                    cycle,
+                    frame,
+                    pc,
+                    opcode,
+                    mnemonic,
+                    registers,
+                    chip8.i(),
+                    chip8.sp(),
+                    chip8.dt,
+                    chip8.st
+                )
+            }
+            TraceFormat::Jsonl => {
+                let registers: Vec<String> = registers.iter().map(|v| v.to_string()).collect();
+                writeln!(
+                    self.writer,
+                    r#"{{"cycle":{},"frame":{},"pc":{},"opcode":{},"mnemonic":"{}","v":[{}],"i":{},"sp":{},"dt":{},"st":{}}}"#,
+                    cycle,
+                    frame,
+                    pc,
+                    opcode,
+                    mnemonic,
+                    registers.join(","),
+                    chip8.i(),
+                    chip8.sp(),
+                    chip8.dt,
+                    chip8.st
+                )
+            }
+        }
+    }
+
+    /// Write any buffered records
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Parse an inclusive range of addresses as `<start>-<end>`, each as hex with 0x prefix or decimal
+pub fn parse_address_range(s: &str) -> Result<RangeInclusive<usize>, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("invalid range {s}, expected <start>-<end>"))?;
+
+    Ok(parse_address(start)?..=parse_address(end)?)
+}
+
+/// Parse an inclusive range of frames as `<start>-<end>`
+pub fn parse_frame_range(s: &str) -> Result<RangeInclusive<u64>, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("invalid range {s}, expected <start>-<end>"))?;
+    let frame = |f: &str| f.parse().map_err(|_| format!("invalid frame {f}"));
+
+    Ok(frame(start)?..=frame(end)?)
+}